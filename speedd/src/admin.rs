@@ -0,0 +1,299 @@
+use crate::{
+    cameras::{CameraHealth, Cameras, Conflict},
+    collector::RoadStatus,
+    fines::Ledger,
+    metrics::METRICS,
+    plates::PlateRules,
+    sessions::{Session, Sessions},
+    shards::Shards,
+    trajectory::Trajectory,
+};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    routing::get,
+    Json, Router,
+};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    future::Future,
+};
+use tokio::net::TcpListener;
+
+/// Read-only JSON views of the collector and the connected clients:
+///
+/// * `GET /roads`: known roads, their limits, queued tickets and dispatcher count
+/// * `GET /connections`: connected clients and what they identified as
+/// * `GET /cameras`: connected cameras by connection, how recently and often they report, and how far
+///   their clocks are off
+/// * `GET /conflicts`: recent cameras disagreeing on a road's limit or sharing a position
+/// * `GET /plates`: ticketed days per plate
+/// * `GET /plates/{plate}`: ticketed days of one plate, normalized like the reports
+/// * `GET /plates/{plate}/ledger`: tickets issued to one plate with their fines, and the total
+/// * `GET /plates/{plate}/trajectory`: cameras which saw one plate on any road in time order, with
+///   the speed since the previous camera on the same road
+/// * `GET /metrics`: Prometheus metrics
+#[derive(Clone, Debug)]
+pub struct Admin {
+    shards: Shards,
+    sessions: Sessions,
+    cameras: Cameras,
+    plates: PlateRules,
+}
+
+impl Admin {
+    pub fn new(shards: Shards, sessions: Sessions, cameras: Cameras) -> Self {
+        Self {
+            shards,
+            sessions,
+            cameras,
+            plates: PlateRules::default(),
+        }
+    }
+
+    /// Normalizes the plates asked for like the cameras' reports.
+    pub fn with_plate_rules(mut self, plates: PlateRules) -> Self {
+        self.plates = plates;
+        self
+    }
+
+    pub fn router(self) -> Router {
+        Router::new()
+            .route("/roads", get(roads))
+            .route("/connections", get(connections))
+            .route("/cameras", get(cameras))
+            .route("/conflicts", get(conflicts))
+            .route("/plates", get(plates))
+            .route("/plates/{plate}", get(plate))
+            .route("/plates/{plate}/ledger", get(ledger))
+            .route("/plates/{plate}/trajectory", get(trajectory))
+            .route("/metrics", get(metrics))
+            .with_state(self)
+    }
+
+    /// Serves until `shutdown` completes and the requests in progress are answered.
+    pub async fn serve(
+        self,
+        listener: TcpListener,
+        shutdown: impl Future<Output = ()> + Send + 'static,
+    ) -> anyhow::Result<()> {
+        tracing::info!("Serving admin interface on {}", listener.local_addr()?);
+        axum::serve(listener, self.router())
+            .with_graceful_shutdown(shutdown)
+            .await?;
+        tracing::info!("Stopped serving the admin interface");
+        Ok(())
+    }
+}
+
+fn unavailable(e: anyhow::Error) -> StatusCode {
+    tracing::error!("Collector did not answer: {e:?}");
+    StatusCode::SERVICE_UNAVAILABLE
+}
+
+async fn roads(State(admin): State<Admin>) -> Result<Json<Vec<RoadStatus>>, StatusCode> {
+    admin.shards.roads().await.map(Json).map_err(unavailable)
+}
+
+async fn connections(State(admin): State<Admin>) -> Json<Vec<Session>> {
+    Json(admin.sessions.list())
+}
+
+async fn cameras(State(admin): State<Admin>) -> Json<Vec<CameraHealth>> {
+    Json(admin.cameras.health())
+}
+
+async fn conflicts(State(admin): State<Admin>) -> Json<Vec<Conflict>> {
+    Json(admin.cameras.conflicts())
+}
+
+async fn plates(
+    State(admin): State<Admin>,
+) -> Result<Json<BTreeMap<String, BTreeSet<u32>>>, StatusCode> {
+    admin
+        .shards
+        .ticketed_days()
+        .await
+        .map(Json)
+        .map_err(unavailable)
+}
+
+async fn plate(
+    State(admin): State<Admin>,
+    Path(plate): Path<String>,
+) -> Result<Json<BTreeSet<u32>>, StatusCode> {
+    let plate = admin
+        .plates
+        .normalize(&plate)
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+    admin
+        .shards
+        .plate(plate)
+        .await
+        .map_err(unavailable)?
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn ledger(
+    State(admin): State<Admin>,
+    Path(plate): Path<String>,
+) -> Result<Json<Ledger>, StatusCode> {
+    let plate = admin
+        .plates
+        .normalize(&plate)
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+    admin
+        .shards
+        .ledger(plate)
+        .await
+        .map_err(unavailable)?
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn trajectory(
+    State(admin): State<Admin>,
+    Path(plate): Path<String>,
+) -> Result<Json<Trajectory>, StatusCode> {
+    let plate = admin
+        .plates
+        .normalize(&plate)
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+    admin
+        .shards
+        .trajectory(plate)
+        .await
+        .map_err(unavailable)?
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn metrics(State(admin): State<Admin>) -> Result<String, StatusCode> {
+    let roads = admin.shards.roads().await.map_err(unavailable)?;
+    METRICS.set_roads(&roads);
+    METRICS.render().map_err(|e| {
+        tracing::error!("Failed to render metrics: {e:?}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        cameras::{CameraGuard, Conflict},
+        collector::Collector,
+        config::Queues,
+    };
+    use speedd_codecs::{camera::Camera, plate::PlateRecord};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    async fn get(addr: std::net::SocketAddr, path: &str) -> String {
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(format!("GET {path} HTTP/1.0\r\nHost: localhost\r\n\r\n").as_bytes())
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    /// Serves two reports of plate ABC on road 12, which make one ticket, and two cameras at the
+    /// same position.
+    async fn serve() -> (std::net::SocketAddr, Vec<Result<CameraGuard, Conflict>>) {
+        let shards = Shards::spawn(vec![Collector::new()], &Queues::default());
+        for (timestamp, mile) in [(1, 2), (20, 4)] {
+            let record = PlateRecord {
+                plate: "ABC".to_string(),
+                timestamp,
+            };
+            let camera = Camera {
+                road: 12,
+                mile,
+                limit: 10,
+            };
+            shards.report(record, camera).await.unwrap();
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        // Wait until the collector has processed both reports
+        while shards
+            .roads()
+            .await
+            .unwrap()
+            .first()
+            .map(|r| r.queued_tickets)
+            != Some(1)
+        {
+            tokio::task::yield_now().await;
+        }
+        let cameras = Cameras::default();
+        let camera = Camera {
+            road: 12,
+            mile: 2,
+            limit: 10,
+        };
+        let guards = vec![
+            cameras.register(0, camera.clone()),
+            cameras.register(1, camera),
+        ];
+        tokio::spawn(
+            Admin::new(shards, Sessions::default(), cameras.clone())
+                .serve(listener, futures::future::pending()),
+        );
+        (addr, guards)
+    }
+
+    #[tokio::test]
+    async fn serves_roads() {
+        let (addr, _cameras) = serve().await;
+        let roads = get(addr, "/roads").await;
+        assert!(roads.ends_with(
+            r#"[{"road":12,"limit":10,"queued_tickets":1,"spilled_tickets":0,"dispatchers":0}]"#
+        ));
+    }
+
+    #[tokio::test]
+    async fn serves_ticketed_days_of_known_plates_only() {
+        let (addr, _cameras) = serve().await;
+        let plate = get(addr, "/plates/ABC").await;
+        assert!(plate.ends_with("[0]"));
+        let unknown = get(addr, "/plates/XYZ").await;
+        assert!(unknown.starts_with("HTTP/1.0 404"));
+    }
+
+    #[tokio::test]
+    async fn serves_ledger() {
+        let (addr, _cameras) = serve().await;
+        let ledger = get(addr, "/plates/ABC/ledger").await;
+        assert!(ledger
+            .ends_with(r#""limit_mph":10,"over_centi_mph":36900,"fine":0}],"outstanding":0}"#));
+    }
+
+    #[tokio::test]
+    async fn serves_trajectory() {
+        let (addr, _cameras) = serve().await;
+        let trajectory = get(addr, "/plates/ABC/trajectory").await;
+        assert!(trajectory.ends_with(r#"{"road":12,"mile":4,"timestamp":20,"speed":37895}]}"#));
+    }
+
+    #[tokio::test]
+    async fn serves_camera_health_and_conflicts() {
+        let (addr, _cameras) = serve().await;
+        let health = get(addr, "/cameras").await;
+        assert!(health.contains(r#"[{"session":0,"road":12,"mile":2,"limit":10,"#));
+        assert!(health.contains(r#"{"session":1,"road":12,"mile":2,"limit":10,"#));
+        let conflicts = get(addr, "/conflicts").await;
+        assert!(conflicts.ends_with(r#"[{"kind":"position","road":12,"mile":2}]"#));
+    }
+
+    #[tokio::test]
+    async fn serves_metrics() {
+        let (addr, _cameras) = serve().await;
+        let metrics = get(addr, "/metrics").await;
+        assert!(metrics.contains(r#"speedd_queued_tickets{road="12"} 1"#));
+    }
+}
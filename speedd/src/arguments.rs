@@ -0,0 +1,176 @@
+use crate::{
+    cameras::ConflictPolicy,
+    config::{LogFormat, PolicyKind},
+};
+use clap::{Parser, Subcommand};
+use std::{net::SocketAddr, path::PathBuf};
+use tracing_subscriber::filter::LevelFilter;
+
+/// Speed Daemon server. Arguments given here take precedence over the configuration file.
+#[derive(Debug, Parser)]
+#[command(author, version)]
+pub struct Arguments {
+    /// TOML configuration file
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
+
+    /// TCP socket to listen on
+    #[arg(short, long)]
+    pub address: Option<SocketAddr>,
+
+    /// Directory to persist the collector state in (off by default)
+    #[arg(short, long)]
+    pub storage: Option<PathBuf>,
+
+    /// Milliseconds between syncs of the storage journal to disk, 0 to sync every observation
+    #[arg(long)]
+    pub journal_sync: Option<u64>,
+
+    /// PEM file with the certificate chain to serve TLS with (off by default)
+    #[arg(long)]
+    pub tls_certificate: Option<PathBuf>,
+
+    /// PEM file with the private key of the TLS certificate
+    #[arg(long)]
+    pub tls_key: Option<PathBuf>,
+
+    /// TCP socket to serve the admin interface on (off by default)
+    #[arg(long)]
+    pub admin: Option<SocketAddr>,
+
+    /// Maximum log level
+    #[arg(long)]
+    pub log_level: Option<LevelFilter>,
+
+    /// Log output format
+    #[arg(long, value_enum)]
+    pub log_format: Option<LogFormat>,
+
+    /// Length of a day in seconds, for ticketing at most once per plate and day
+    #[arg(long)]
+    pub seconds_per_day: Option<u32>,
+
+    /// Days to remember observations and ticketed days for, counted back from the newest observation
+    /// (forever by default)
+    #[arg(long)]
+    pub retention_days: Option<u32>,
+
+    /// File to append a JSON line to for every issued and suppressed ticket (off by default)
+    #[arg(long)]
+    pub audit_log: Option<PathBuf>,
+
+    /// File to record every decoded client message to, with its connection, role and arrival
+    /// time, for `speedd_benchy trace` to replay (off by default). Replaces an earlier capture
+    #[arg(long)]
+    pub capture: Option<PathBuf>,
+
+    /// What to do about cameras disagreeing on a road's limit or sharing a position
+    #[arg(long, value_enum)]
+    pub camera_conflicts: Option<ConflictPolicy>,
+
+    /// Keep connections open after sending an error to the client, instead of closing them as the spec demands
+    #[arg(long)]
+    pub lenient: bool,
+
+    /// Number of collector tasks to partition plates across (defaults to the number of cores)
+    #[arg(long)]
+    pub shards: Option<usize>,
+
+    /// Which observations make a ticket, on roads without a policy of their own
+    #[arg(long, value_enum)]
+    pub policy: Option<PolicyKind>,
+
+    /// Hundredths of mph a car may exceed the limit by, for the exact policy
+    #[arg(long)]
+    pub tolerance: Option<u16>,
+
+    /// Seconds a client gets to identify as camera or dispatcher (unlimited by default)
+    #[arg(long)]
+    pub identification_timeout: Option<u64>,
+
+    /// Seconds a camera which did not ask for heartbeats may stay silent (unlimited by default)
+    #[arg(long)]
+    pub idle_timeout: Option<u64>,
+
+    /// Connections allowed from a single IP address at a time (unlimited by default)
+    #[arg(long)]
+    pub connections_per_ip: Option<usize>,
+
+    /// Plate reports per second and camera connection, on average (unlimited by default)
+    #[arg(long)]
+    pub plates_per_second: Option<u32>,
+
+    /// Plate reports a camera may send in a burst (defaults to the rate per second)
+    #[arg(long)]
+    pub plate_burst: Option<u32>,
+
+    /// Upper-case plates and strip everything but letters and digits from them
+    #[arg(long)]
+    pub normalize_plates: bool,
+
+    /// Read the letters O, I and B in plates as the digits 0, 1 and 8
+    #[arg(long)]
+    pub ocr_confusions: bool,
+
+    /// JSONL file to append every issued ticket to, in addition to the configured sinks
+    #[arg(long)]
+    pub ticket_jsonl: Vec<PathBuf>,
+
+    /// CSV file to append every issued ticket to, in addition to the configured sinks
+    #[arg(long)]
+    pub ticket_csv: Vec<PathBuf>,
+
+    /// URL to POST every issued ticket to as JSON, in addition to the configured sinks
+    #[arg(long)]
+    pub ticket_webhook: Vec<String>,
+
+    /// Seconds dispatchers get to pick up the queued tickets when shutting down
+    #[arg(long)]
+    pub drain_timeout: Option<u64>,
+
+    /// File to save undelivered tickets to when shutting down without storage, and to load them from on startup
+    #[arg(long)]
+    pub ticket_backlog: Option<PathBuf>,
+
+    /// Capacity of the queue of plate reports into each collector
+    #[arg(long)]
+    pub reporting_capacity: Option<usize>,
+
+    /// Capacity of the queue of dispatcher subscriptions into each collector
+    #[arg(long)]
+    pub subscription_capacity: Option<usize>,
+
+    /// Capacity of the per-road ticket queues
+    #[arg(long)]
+    pub ticket_capacity: Option<usize>,
+
+    /// Tickets a dispatcher which acknowledges may have unacknowledged before it gets more
+    #[arg(long)]
+    pub unacked_tickets: Option<usize>,
+
+    /// Capacity of the queue of tickets into each ticket sink
+    #[arg(long)]
+    pub sink_capacity: Option<usize>,
+
+    /// Tickets per road kept in memory beyond the queue capacity, before spilling to disk
+    #[arg(long)]
+    pub spill_threshold: Option<usize>,
+
+    /// Directory for spilled tickets (defaults to the system's temporary directory)
+    #[arg(long)]
+    pub spill_dir: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum Command {
+    /// Computes the tickets for a log of observations, instead of serving.
+    /// Prints an audit log entry for every violation found. Partitions the plates across as many
+    /// collectors as the server would, since the retention window moves per shard.
+    Tickets {
+        /// JSONL file of `[plate record, camera]` pairs, such as a storage journal
+        observations: PathBuf,
+    },
+}
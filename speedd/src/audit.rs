@@ -0,0 +1,119 @@
+use crate::{
+    jsonl::{self, JsonlAppender},
+    persistence::Observation,
+};
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use speedd_codecs::server::TicketRecord;
+use std::{io::Write, path::Path};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Issued,
+    /// The plate was already ticketed on one of the days the ticket covers
+    Suppressed,
+}
+
+/// One line of the audit log: a violation, what became of it, and the two observations it was computed from.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub outcome: Outcome,
+    pub ticket: TicketRecord,
+    pub observations: [Observation; 2],
+}
+
+impl AuditEntry {
+    /// `observations` are the plate reads and cameras behind the ticket, in time order.
+    pub fn new(outcome: Outcome, ticket: &TicketRecord, observations: [Observation; 2]) -> Self {
+        Self {
+            outcome,
+            ticket: ticket.clone(),
+            observations,
+        }
+    }
+}
+
+/// Append-only JSONL log of [`AuditEntry`]s, shared by all collector shards.
+#[derive(Clone, Debug)]
+pub struct AuditLog {
+    log: JsonlAppender,
+}
+
+impl AuditLog {
+    pub fn new(writer: impl Write + Send + 'static) -> anyhow::Result<Self> {
+        Ok(Self {
+            log: JsonlAppender::spawn("audit log", writer)?,
+        })
+    }
+
+    /// Opens the log file for appending, creating it if necessary.
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        Self::new(jsonl::open_append(path, "audit log")?)
+    }
+
+    pub fn record(&self, entry: &AuditEntry) -> anyhow::Result<()> {
+        self.log
+            .append(entry)
+            .context("Failed to write to audit log")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{collector::Collector, config, plates::PlateRules};
+    use speedd_codecs::{camera::Camera, plate::PlateRecord};
+
+    #[test]
+    fn records_issued_and_suppressed_tickets() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let rules = PlateRules::new(config::Plates {
+            uppercase: true,
+            ..config::Plates::default()
+        });
+        let mut collector = Collector::new()
+            .with_plate_rules(rules)
+            .with_audit_log(AuditLog::open(file.path()).unwrap());
+        // The last camera re-registered with a higher limit
+        for (plate, timestamp, mile, limit) in
+            [("ABC", 1, 2, 10), ("abc", 20, 4, 10), ("Abc", 40, 8, 20)]
+        {
+            let record = PlateRecord {
+                plate: plate.to_string(),
+                timestamp,
+            };
+            let camera = Camera {
+                road: 12,
+                mile,
+                limit,
+            };
+            collector.observe(record, camera).unwrap();
+        }
+        // Waits for the log to be written
+        drop(collector);
+
+        let entries = jsonl::read::<AuditEntry>(file.path(), "audit log").unwrap();
+        assert_eq!(
+            entries
+                .iter()
+                .map(|entry| (entry.outcome, entry.ticket.timestamp1))
+                .collect::<Vec<_>>(),
+            [(Outcome::Issued, 1), (Outcome::Suppressed, 20)]
+        );
+        let observations = entries[1]
+            .observations
+            .iter()
+            .map(|(record, camera)| {
+                (
+                    record.plate.as_str(),
+                    record.timestamp,
+                    camera.mile,
+                    camera.limit,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(observations, [("abc", 20, 4, 10), ("Abc", 40, 8, 20)]);
+        assert_eq!(entries[1].ticket.plate, "ABC");
+    }
+}
@@ -1,98 +1,262 @@
-use crate::{heartbeat, server};
+use crate::{
+    cameras::CameraGuard, heartbeat::Heartbeat, metrics::METRICS, plates::PlateRules,
+    ratelimit::TokenBucket, shards::Shards,
+};
 use futures::{Sink, SinkExt, Stream, StreamExt};
-use speedd_codecs::{camera::Camera, client, plate::PlateRecord};
-use tokio::sync::mpsc;
+use speedd_codecs::{camera::Camera, client, server};
+use std::time::Duration;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
 
 pub struct CameraClient {
     cam: Camera,
+    idle_timeout: Option<Duration>,
+    strict: bool,
+    plate_limit: Option<(u32, u32)>,
+    plates: PlateRules,
+    registration: Option<CameraGuard>,
+    shutdown: CancellationToken,
 }
 
 impl CameraClient {
     pub fn new(cam: Camera) -> Self {
-        Self { cam }
+        Self {
+            cam,
+            idle_timeout: None,
+            strict: true,
+            plate_limit: None,
+            plates: PlateRules::default(),
+            registration: None,
+            shutdown: CancellationToken::new(),
+        }
+    }
+
+    /// Whether to disconnect after sending an error, as the spec demands. On by default.
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Disconnects the camera if it sends nothing for this long, unless it asked for heartbeats.
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Limits the plate reports to `rate` per second, with bursts of up to `burst` reports.
+    /// A camera going faster is not read from until it may report again.
+    pub fn with_plate_limit(mut self, rate: u32, burst: u32) -> Self {
+        self.plate_limit = Some((rate, burst));
+        self
+    }
+
+    /// Drops the plate reads which are invalid under these rules. By default, only empty plates
+    /// are dropped. The collectors normalize the plates they receive by the same rules.
+    pub fn with_plate_rules(mut self, plates: PlateRules) -> Self {
+        self.plates = plates;
+        self
+    }
+
+    /// Keeps the camera registered while it runs, reporting its plates to the registry and
+    /// logging when it falls silent.
+    pub fn with_registration(mut self, registration: CameraGuard) -> Self {
+        self.registration = Some(registration);
+        self
+    }
+
+    /// Disconnects the camera when `shutdown` is cancelled.
+    pub fn with_shutdown(mut self, shutdown: CancellationToken) -> Self {
+        self.shutdown = shutdown;
+        self
     }
 
     pub async fn run<R, W>(
         self,
         mut reader: R,
         mut writer: W,
-        plate_tx: mpsc::Sender<(PlateRecord, Camera)>,
-        mut heartbeat_sender: Option<mpsc::Sender<()>>,
-        mut heartbeat_receiver: mpsc::Receiver<()>,
+        shards: Shards,
+        mut heartbeat: Heartbeat,
     ) -> anyhow::Result<()>
     where
         R: Stream<Item = Result<client::Message, anyhow::Error>> + Send + Unpin,
         W: Sink<server::Message, Error = anyhow::Error> + Send + Unpin,
     {
         tracing::info!("Starting Camera Client loop");
+        let mut last_message = Instant::now();
+        let mut plate_tokens = self
+            .plate_limit
+            .map(|(rate, burst)| TokenBucket::new(rate, burst));
+        let mut throttled_before = false;
+        let mut last_plate = Instant::now();
+        let mut silent = false;
+        let silence = self.registration.as_ref().map(CameraGuard::silence);
         loop {
+            let idle_deadline = last_message + self.idle_timeout.unwrap_or_default();
+            let heartbeating = heartbeat.is_running();
+            let next_token = plate_tokens.as_mut().and_then(TokenBucket::next_token);
             tokio::select! {
-                Some(msg) = reader.next() => {
+                Some(msg) = reader.next(), if next_token.is_none() => {
+                    last_message = Instant::now();
                     match msg {
                         Ok(msg) => {
                             tracing::trace!("Received camera message {msg:?}");
-                            self.handle_client_message(msg, &mut writer, &plate_tx, &mut heartbeat_sender).await?;
+                            if let client::Message::Plate(_) = &msg {
+                                if let Some(tokens) = &mut plate_tokens {
+                                    tokens.take();
+                                }
+                                last_plate = Instant::now();
+                                if silent {
+                                    tracing::info!("{:?} reports plates again", self.cam);
+                                    METRICS.cameras_silent.dec();
+                                    silent = false;
+                                }
+                            }
+                            if let Some(error) = self.handle_client_message(msg, &shards, &mut heartbeat).await? {
+                                writer.send(server::Message::Error(error)).await?;
+                                if self.strict {
+                                    break;
+                                }
+                            }
+                        }
+                        Err(e) => {
+                            METRICS.decode_errors.inc();
+                            writer.send(server::Message::Error(format!("Nahh... you're just a camera. {e}"))).await?;
+                            if self.strict {
+                                break;
+                            }
                         }
-                        Err(e) => writer.send(server::Message::Error(format!("Nahh... you're just a camera. {e:?}"))).await?,
                     }
                 }
-                Some(()) = heartbeat_receiver.recv() => {
-                    writer.send(server::Message::Heartbeat).await?;
+                () = self.shutdown.cancelled() => {
+                    writer.send(server::Message::Error("Server is shutting down".to_string())).await?;
+                    break;
+                }
+                _ = tokio::time::sleep_until(next_token.unwrap_or_else(Instant::now)), if next_token.is_some() => {
+                    if !throttled_before {
+                        tracing::warn!("Throttling plate reports of {:?}", self.cam);
+                        throttled_before = true;
+                    }
+                    METRICS.plates_throttled.inc();
+                    // Not reading is not being idle
+                    last_message = Instant::now();
+                }
+                _ = tokio::time::sleep_until(last_plate + silence.unwrap_or_default()), if silence.is_some() && !silent => {
+                    tracing::warn!("{:?} has not reported a plate for {:?}", self.cam, silence.unwrap_or_default());
+                    METRICS.cameras_silent.inc();
+                    silent = true;
+                }
+                _ = tokio::time::sleep_until(idle_deadline), if self.idle_timeout.is_some() && !heartbeating && next_token.is_none() => {
+                    tracing::info!("Disconnecting idle {:?}", self.cam);
+                    writer.send(server::Message::Error("You have been quiet for too long".to_string())).await?;
+                    break;
                 }
                 else => break,
             }
         }
+        if silent {
+            METRICS.cameras_silent.dec();
+        }
         tracing::info!("Leaving Camera Client loop");
         Ok(())
     }
 
-    async fn handle_client_message<W>(
+    /// Acts on a message from the camera. Returns the error to send if the message was illegal.
+    async fn handle_client_message(
         &self,
         msg: client::Message,
-        writer: &mut W,
-        plate_tx: &mpsc::Sender<(PlateRecord, Camera)>,
-        heartbeat_sender: &mut Option<mpsc::Sender<()>>,
-    ) -> anyhow::Result<()>
-    where
-        W: Sink<server::Message, Error = anyhow::Error> + Send + Unpin,
-    {
-        match msg {
-            client::Message::Plate(record) => {
-                plate_tx.send((record, self.cam.clone())).await?;
-            }
-            client::Message::WantHeartbeat(dur) => {
-                if let Some(heartbeat_sender) = heartbeat_sender.take() {
-                    if dur.is_zero() {
-                        tracing::warn!("Ignoring zero-duration heartbeat");
-                    } else {
-                        tracing::info!("Spawning a new heartbeat");
-                        tokio::spawn(heartbeat::run(dur, heartbeat_sender));
+        shards: &Shards,
+        heartbeat: &mut Heartbeat,
+    ) -> anyhow::Result<Option<String>> {
+        let error = match msg {
+            // A bad read is the camera's OCR failing, not the client breaking the protocol
+            client::Message::Plate(record) => match self.plates.normalize(&record.plate) {
+                Ok(_) => {
+                    if let Some(registration) = &self.registration {
+                        registration.report(record.timestamp);
                     }
-                } else {
-                    tracing::info!("Ignoring repeated heartbeat request");
-                    writer
-                        .send(server::Message::Error(
-                            "You already specified a heartbeat".to_string(),
-                        ))
-                        .await?;
+                    shards.report(record, self.cam.clone()).await?;
+                    None
                 }
-            }
+                Err(rejection) => {
+                    tracing::warn!(
+                        "Dropping plate {:?} from {:?}: {rejection}",
+                        record.plate,
+                        self.cam
+                    );
+                    METRICS.plates_rejected.inc();
+                    if let Some(registration) = &self.registration {
+                        registration.reject();
+                    }
+                    None
+                }
+            },
+            client::Message::WantHeartbeat(dur) => heartbeat.request(dur).err(),
             client::Message::IAmCamera { .. } => {
                 tracing::warn!("Ignoring repeated IAmCamera");
-                writer
-                    .send(server::Message::Error(
-                        "Yes, you are (a camera)".to_string(),
-                    ))
-                    .await?;
+                Some("Yes, you are (a camera)")
             }
-            client::Message::IAmDispatcher(_roads) => {
-                writer
-                    .send(server::Message::Error(
-                        "No you're not (a dispatcher)".to_string(),
-                    ))
-                    .await?;
+            client::Message::IAmDispatcher(_roads) => Some("No you're not (a dispatcher)"),
+            client::Message::WantAcks | client::Message::Ack(_) => {
+                Some("Cameras don't get tickets to acknowledge")
             }
-        }
-        Ok(())
+        };
+        Ok(error.map(String::from))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{collector::Collector, config::Queues, heartbeat::Heartbeats};
+    use std::time::Duration;
+
+    fn message_writer() -> (
+        impl Sink<server::Message, Error = anyhow::Error> + Send + Unpin,
+        futures::channel::mpsc::UnboundedReceiver<server::Message>,
+    ) {
+        let (tx, rx) = futures::channel::mpsc::unbounded();
+        (tx.sink_map_err(anyhow::Error::from), rx)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn disconnects_idle_camera_without_heartbeat() {
+        let shards = Shards::spawn(vec![Collector::new()], &Queues::default());
+        let camera = Camera {
+            road: 1,
+            mile: 2,
+            limit: 60,
+        };
+
+        let (writer, mut written) = message_writer();
+        let (writer, heartbeat) = Heartbeats::default().connection(writer);
+        let start = Instant::now();
+        CameraClient::new(camera.clone())
+            .with_idle_timeout(Duration::from_secs(30))
+            .run(
+                futures::stream::pending(),
+                writer,
+                shards.clone(),
+                heartbeat,
+            )
+            .await
+            .unwrap();
+        assert_eq!(start.elapsed(), Duration::from_secs(30));
+        assert!(matches!(
+            written.next().await,
+            Some(server::Message::Error(_))
+        ));
+
+        // A camera with heartbeats stays connected
+        let (writer, _written) = message_writer();
+        let (writer, heartbeat) = Heartbeats::default().connection(writer);
+        let reader =
+            futures::stream::iter([Ok(client::Message::WantHeartbeat(Duration::from_secs(10)))])
+                .chain(futures::stream::pending());
+        let client = CameraClient::new(camera)
+            .with_idle_timeout(Duration::from_secs(30))
+            .run(reader, writer, shards, heartbeat);
+        tokio::time::timeout(Duration::from_secs(300), client)
+            .await
+            .unwrap_err();
     }
 }
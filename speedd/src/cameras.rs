@@ -0,0 +1,524 @@
+use crate::metrics::METRICS;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use speedd_codecs::{camera::Camera, Limit, Mile, Road, Timestamp};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::time::Instant;
+
+/// Number of conflicts remembered for the admin interface.
+const CONFLICT_HISTORY: usize = 1000;
+
+/// Number of recent reports a camera's clock offset is estimated from.
+const OFFSET_SAMPLES: usize = 32;
+
+/// Time constant of the moving average of the report rates.
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// What to do about a camera which does not agree with the cameras announced before it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, Deserialize, Serialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictPolicy {
+    /// Log and record the conflict, but accept the camera as announced
+    #[default]
+    Warn,
+    /// Refuse the camera with an error
+    Reject,
+    /// Accept a camera with a conflicting limit, but with the limit announced first.
+    /// Refuse a camera at an occupied position
+    FirstWins,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Conflict {
+    /// The camera announced a different limit than the first camera on its road
+    Limit {
+        road: Road,
+        limit: Limit,
+        announced: Limit,
+    },
+    /// Another connected camera already sits at this position
+    Position { road: Road, mile: Mile },
+}
+
+impl Conflict {
+    fn label(&self) -> &'static str {
+        match self {
+            Conflict::Limit { .. } => "limit",
+            Conflict::Position { .. } => "position",
+        }
+    }
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Conflict::Limit {
+                road,
+                limit,
+                announced,
+            } => write!(
+                f,
+                "Road {road} has a limit of {limit} mph, not {announced} mph"
+            ),
+            Conflict::Position { road, mile } => {
+                write!(f, "There already is a camera at mile {mile} of road {road}")
+            }
+        }
+    }
+}
+
+/// How a connected camera is doing, as shown on the admin interface.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct CameraHealth {
+    /// Session of the camera's connection, which tells apart cameras sharing a position
+    pub session: u64,
+    pub road: Road,
+    pub mile: Mile,
+    pub limit: Limit,
+    /// Timestamp of the last plate reported
+    pub last_timestamp: Option<Timestamp>,
+    /// Seconds since the last plate report, or since the camera connected if it reported none
+    pub idle_seconds: f64,
+    /// Moving average over about a minute
+    pub reports_per_minute: f64,
+    /// Plate reads which failed validation
+    pub rejected_plates: u64,
+    /// Seconds the camera's clock is ahead of the median of the cameras on its road
+    pub skew: Option<f64>,
+    /// Idle for longer than allowed
+    pub silent: bool,
+    /// Skewed by more than allowed
+    pub skewed: bool,
+}
+
+/// Reports of a connected camera.
+#[derive(Debug)]
+struct Reports {
+    road: Road,
+    mile: Mile,
+    limit: Limit,
+    since: Instant,
+    last_report: Option<(Instant, Timestamp)>,
+    /// Reports per minute as of `rate_updated`
+    rate: f64,
+    rate_updated: Instant,
+    /// Reported timestamps minus the server's clock at arrival, in seconds. Reports only ever
+    /// arrive late, so the largest of them is the best estimate of the camera's clock offset.
+    offsets: VecDeque<f64>,
+    skewed: bool,
+    rejected: u64,
+}
+
+impl Reports {
+    fn new(camera: &Camera) -> Self {
+        let now = Instant::now();
+        Self {
+            road: camera.road,
+            mile: camera.mile,
+            limit: camera.limit,
+            since: now,
+            last_report: None,
+            rate: 0.0,
+            rate_updated: now,
+            offsets: VecDeque::new(),
+            skewed: false,
+            rejected: 0,
+        }
+    }
+
+    fn rate(&self, now: Instant) -> f64 {
+        let elapsed = now.duration_since(self.rate_updated).as_secs_f64();
+        self.rate * (-elapsed / RATE_WINDOW.as_secs_f64()).exp()
+    }
+
+    fn offset(&self) -> Option<f64> {
+        self.offsets.iter().copied().reduce(f64::max)
+    }
+}
+
+#[derive(Debug)]
+struct Registry {
+    /// Limit of each road, as announced by its first camera
+    limits: HashMap<Road, Limit>,
+    /// Connected cameras per position
+    positions: HashMap<(Road, Mile), usize>,
+    /// Reports of the connected cameras per session
+    reports: HashMap<u64, Reports>,
+    conflicts: VecDeque<Conflict>,
+    /// Reference point of the server's clock for the clock offsets
+    start: Instant,
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Self {
+            limits: HashMap::default(),
+            positions: HashMap::default(),
+            reports: HashMap::default(),
+            conflicts: VecDeque::default(),
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Registry {
+    fn record(&mut self, conflict: Conflict) {
+        tracing::warn!("Camera conflict: {conflict}");
+        METRICS
+            .camera_conflicts
+            .with_label_values(&[conflict.label()])
+            .inc();
+        if self.conflicts.len() == CONFLICT_HISTORY {
+            self.conflicts.pop_front();
+        }
+        self.conflicts.push_back(conflict);
+    }
+
+    /// How far the camera's clock is ahead of the median of the cameras on its road, if it and at
+    /// least one other camera there reported something.
+    fn skew(&self, session: u64) -> Option<f64> {
+        let reports = self.reports.get(&session)?;
+        let offset = reports.offset()?;
+        let mut offsets = self
+            .reports
+            .values()
+            .filter(|other| other.road == reports.road)
+            .filter_map(Reports::offset)
+            .collect::<Vec<_>>();
+        if offsets.len() < 2 {
+            return None;
+        }
+        offsets.sort_by(f64::total_cmp);
+        let middle = offsets.len() / 2;
+        let median = if offsets.len() % 2 == 0 {
+            (offsets[middle - 1] + offsets[middle]) / 2.0
+        } else {
+            offsets[middle]
+        };
+        Some(offset - median)
+    }
+}
+
+/// Registry of the cameras announced via `IAmCamera`, shared between connection tasks and the admin interface.
+/// Checks every new camera against the limits and positions of the cameras before it.
+///
+/// Also watches the health of the connected cameras: a camera is silent when it did not report a
+/// plate for too long, and skewed when its clock is too far off the other cameras on its road.
+/// The clock offsets are compared, rather than the timestamps, because the protocol's timestamps
+/// have no relation to the server's clock. With only two cameras on a road, each is off the
+/// median by half their difference, so it takes three to tell the culprit.
+#[derive(Clone, Debug)]
+pub struct Cameras {
+    registry: Arc<Mutex<Registry>>,
+    policy: ConflictPolicy,
+    silence: Duration,
+    max_skew: Duration,
+}
+
+impl Default for Cameras {
+    fn default() -> Self {
+        Self::new(ConflictPolicy::default())
+    }
+}
+
+impl Cameras {
+    pub fn new(policy: ConflictPolicy) -> Self {
+        Self {
+            registry: Arc::default(),
+            policy,
+            silence: Duration::from_secs(300),
+            max_skew: Duration::from_secs(60),
+        }
+    }
+
+    /// Flags cameras which did not report for longer than `silence`, or whose clock is off by more than
+    /// `max_skew`. Five minutes and one minute by default.
+    pub fn with_health(mut self, silence: Duration, max_skew: Duration) -> Self {
+        self.silence = silence;
+        self.max_skew = max_skew;
+        self
+    }
+
+    /// Registers a camera newly announced on the connection of `session`, which stays at its
+    /// position until the returned guard is dropped.
+    /// Returns the conflict instead if the policy refuses the camera.
+    pub fn register(&self, session: u64, mut camera: Camera) -> Result<CameraGuard, Conflict> {
+        let mut registry = self.registry.lock().unwrap();
+        let position = (camera.road, camera.mile);
+        if registry.positions.get(&position).is_some_and(|n| *n > 0) {
+            let conflict = Conflict::Position {
+                road: camera.road,
+                mile: camera.mile,
+            };
+            registry.record(conflict.clone());
+            if self.policy != ConflictPolicy::Warn {
+                return Err(conflict);
+            }
+        }
+        let limit = *registry.limits.entry(camera.road).or_insert(camera.limit);
+        if limit != camera.limit {
+            let conflict = Conflict::Limit {
+                road: camera.road,
+                limit,
+                announced: camera.limit,
+            };
+            registry.record(conflict.clone());
+            match self.policy {
+                ConflictPolicy::Warn => {}
+                ConflictPolicy::Reject => return Err(conflict),
+                ConflictPolicy::FirstWins => camera.limit = limit,
+            }
+        }
+        *registry.positions.entry(position).or_default() += 1;
+        registry.reports.insert(session, Reports::new(&camera));
+        Ok(CameraGuard {
+            session,
+            camera,
+            cameras: self.clone(),
+        })
+    }
+
+    /// Health of the connected cameras, by road, mile and session.
+    pub fn health(&self) -> Vec<CameraHealth> {
+        let registry = self.registry.lock().unwrap();
+        let now = Instant::now();
+        let mut health = registry
+            .reports
+            .iter()
+            .map(|(&session, reports)| {
+                let last = reports.last_report.map_or(reports.since, |(at, _)| at);
+                let idle = now.duration_since(last);
+                let skew = registry.skew(session);
+                CameraHealth {
+                    session,
+                    road: reports.road,
+                    mile: reports.mile,
+                    limit: reports.limit,
+                    last_timestamp: reports.last_report.map(|(_, timestamp)| timestamp),
+                    idle_seconds: idle.as_secs_f64(),
+                    reports_per_minute: reports.rate(now),
+                    rejected_plates: reports.rejected,
+                    skew,
+                    silent: idle > self.silence,
+                    skewed: skew.is_some_and(|skew| skew.abs() > self.max_skew.as_secs_f64()),
+                }
+            })
+            .collect::<Vec<_>>();
+        health.sort_by_key(|camera| (camera.road, camera.mile, camera.session));
+        health
+    }
+
+    /// Conflicts detected so far, oldest first. Only the most recent ones are kept.
+    pub fn conflicts(&self) -> Vec<Conflict> {
+        self.registry
+            .lock()
+            .unwrap()
+            .conflicts
+            .iter()
+            .cloned()
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+pub struct CameraGuard {
+    session: u64,
+    camera: Camera,
+    cameras: Cameras,
+}
+
+impl CameraGuard {
+    /// The camera as accepted, which may differ from the announced one in its limit.
+    pub fn camera(&self) -> &Camera {
+        &self.camera
+    }
+
+    /// Time without plate reports after which the camera counts as silent.
+    pub fn silence(&self) -> Duration {
+        self.cameras.silence
+    }
+
+    /// Records a plate read of the camera which failed validation.
+    pub fn reject(&self) {
+        if let Some(reports) = self
+            .cameras
+            .registry
+            .lock()
+            .unwrap()
+            .reports
+            .get_mut(&self.session)
+        {
+            reports.rejected += 1;
+        }
+    }
+
+    /// Records a plate report of the camera, and logs when its clock starts or stops being skewed.
+    pub fn report(&self, timestamp: Timestamp) {
+        let mut registry = self.cameras.registry.lock().unwrap();
+        let now = Instant::now();
+        let arrival = now.duration_since(registry.start).as_secs_f64();
+        let Some(reports) = registry.reports.get_mut(&self.session) else {
+            return;
+        };
+        reports.rate = reports.rate(now) + 60.0 / RATE_WINDOW.as_secs_f64();
+        reports.rate_updated = now;
+        reports.last_report = Some((now, timestamp));
+        if reports.offsets.len() == OFFSET_SAMPLES {
+            reports.offsets.pop_front();
+        }
+        reports.offsets.push_back(f64::from(timestamp) - arrival);
+
+        let skew = registry.skew(self.session);
+        let skewed = skew.is_some_and(|skew| skew.abs() > self.cameras.max_skew.as_secs_f64());
+        let reports = registry
+            .reports
+            .get_mut(&self.session)
+            .expect("Reports were there just now");
+        if skewed != reports.skewed {
+            reports.skewed = skewed;
+            let Camera { road, mile, .. } = self.camera;
+            let skew = skew.unwrap_or_default();
+            if skewed {
+                tracing::warn!("Clock of the camera at mile {mile} of road {road} is {skew:.0}s off its neighbours");
+                METRICS.cameras_skewed.inc();
+            } else {
+                tracing::info!("Clock of the camera at mile {mile} of road {road} is back in line, {skew:.0}s off its neighbours");
+                METRICS.cameras_skewed.dec();
+            }
+        }
+    }
+}
+
+impl Drop for CameraGuard {
+    fn drop(&mut self) {
+        let mut registry = self.cameras.registry.lock().unwrap();
+        let position = (self.camera.road, self.camera.mile);
+        if let Some(count) = registry.positions.get_mut(&position) {
+            *count -= 1;
+            if *count == 0 {
+                registry.positions.remove(&position);
+            }
+        }
+        if registry
+            .reports
+            .remove(&self.session)
+            .is_some_and(|reports| reports.skewed)
+        {
+            METRICS.cameras_skewed.dec();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn camera(road: Road, mile: Mile, limit: Limit) -> Camera {
+        Camera { road, mile, limit }
+    }
+
+    #[test]
+    fn conflict_policies() {
+        let cameras = Cameras::new(ConflictPolicy::Warn);
+        let first = cameras.register(0, camera(1, 10, 60)).unwrap();
+        let second = cameras.register(1, camera(1, 10, 80)).unwrap();
+        assert_eq!(second.camera().limit, 80);
+        // Cameras sharing a position keep their own health
+        first.reject();
+        let health = cameras.health();
+        let rejected = health
+            .iter()
+            .map(|camera| (camera.session, camera.limit, camera.rejected_plates))
+            .collect::<Vec<_>>();
+        assert_eq!(rejected, [(0, 60, 1), (1, 80, 0)]);
+        assert_eq!(
+            cameras.conflicts(),
+            [
+                Conflict::Position { road: 1, mile: 10 },
+                Conflict::Limit {
+                    road: 1,
+                    limit: 60,
+                    announced: 80
+                }
+            ]
+        );
+
+        let cameras = Cameras::new(ConflictPolicy::FirstWins);
+        let first = cameras.register(0, camera(1, 10, 60)).unwrap();
+        assert_eq!(
+            cameras
+                .register(1, camera(1, 20, 80))
+                .unwrap()
+                .camera()
+                .limit,
+            60
+        );
+        assert!(cameras.register(2, camera(1, 10, 60)).is_err());
+        // The position is free again once its camera disconnects
+        drop(first);
+        assert!(cameras.register(3, camera(1, 10, 60)).is_ok());
+
+        let cameras = Cameras::new(ConflictPolicy::Reject);
+        let _first = cameras.register(0, camera(1, 10, 60)).unwrap();
+        assert_eq!(
+            cameras
+                .register(1, camera(1, 20, 80))
+                .unwrap_err()
+                .to_string(),
+            "Road 1 has a limit of 60 mph, not 80 mph"
+        );
+        assert!(cameras.register(2, camera(2, 10, 80)).is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn flags_silent_and_skewed_cameras() {
+        let cameras =
+            Cameras::default().with_health(Duration::from_secs(60), Duration::from_secs(30));
+        let guards = [0, 10, 20].map(|mile| {
+            cameras
+                .register(u64::from(mile), camera(1, mile, 60))
+                .unwrap()
+        });
+        let _other_road = cameras.register(30, camera(2, 0, 60)).unwrap();
+        tokio::time::sleep(Duration::from_secs(50)).await;
+
+        // The first two report in time, the third one's clock is five minutes ahead. One report
+        // arrives late, which does not make its camera look skewed.
+        for (guard, timestamp) in guards.iter().zip([1050, 1050, 1350]) {
+            guard.report(timestamp);
+        }
+        guards[0].report(1040);
+        tokio::time::sleep(Duration::from_secs(30)).await;
+        guards[1].report(1080);
+
+        let health = cameras.health();
+        let summary = health
+            .iter()
+            .map(|camera| (camera.mile, camera.skew, camera.skewed, camera.silent))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            [
+                (0, Some(0.0), false, false),
+                (10, Some(0.0), false, false),
+                (20, Some(300.0), true, false),
+                (0, None, false, true),
+            ]
+        );
+        assert_eq!(health[0].last_timestamp, Some(1040));
+        assert_eq!(health[1].idle_seconds, 0.0);
+        assert_eq!(health[0].idle_seconds, 30.0);
+        assert!((health[0].reports_per_minute - 2.0 * (-0.5f64).exp()).abs() < 1e-9);
+
+        // Disconnecting takes the camera out of the picture
+        let [_, _, skewed] = guards;
+        drop(skewed);
+        assert!(cameras.health().iter().all(|camera| !camera.skewed));
+    }
+}
@@ -0,0 +1,159 @@
+//! Recording of the client traffic, to replay production load patterns and bugs with `speedd_benchy`.
+
+use crate::{jsonl::JsonlAppender, sessions::Role};
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use speedd_codecs::client::Message;
+use std::{fs::File, io::Write, path::Path};
+use tokio::time::Instant;
+
+/// One line of a capture: a message as it was decoded, with who sent it and when.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CapturedMessage {
+    /// Id of the connection, as listed on the admin interface
+    pub connection: u64,
+    /// What the client identified as, including the message identifying it
+    pub role: Role,
+    /// Microseconds since the capture started
+    pub micros: u64,
+    pub message: Message,
+}
+
+/// JSONL file of [`CapturedMessage`]s, shared by all connections.
+#[derive(Clone, Debug)]
+pub struct Capture {
+    log: JsonlAppender,
+    start: Instant,
+}
+
+impl Capture {
+    pub fn new(writer: impl Write + Send + 'static) -> anyhow::Result<Self> {
+        Ok(Self {
+            log: JsonlAppender::spawn("capture", writer)?,
+            start: Instant::now(),
+        })
+    }
+
+    /// Creates the capture file, replacing an earlier capture.
+    pub fn create(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let file =
+            File::create(path).with_context(|| format!("Failed to create capture {path:?}"))?;
+        tracing::info!("Capturing client messages to {path:?}");
+        Self::new(file)
+    }
+
+    /// Starts recording the messages of a connection.
+    pub fn connection(&self, connection: u64) -> ConnectionCapture {
+        ConnectionCapture {
+            capture: self.clone(),
+            connection,
+            role: Role::Unidentified,
+        }
+    }
+
+    fn record(&self, message: &CapturedMessage) -> anyhow::Result<()> {
+        self.log
+            .append(message)
+            .context("Failed to write to capture")
+    }
+}
+
+/// Records the messages of one connection, following its role.
+#[derive(Debug)]
+pub struct ConnectionCapture {
+    capture: Capture,
+    connection: u64,
+    role: Role,
+}
+
+impl ConnectionCapture {
+    /// Records a decoded message. Failing to do so is logged, but does not affect the connection.
+    pub fn record(&mut self, message: &Message) {
+        match message {
+            Message::IAmCamera(camera) if self.role == Role::Unidentified => {
+                self.role = Role::Camera(camera.clone());
+            }
+            Message::IAmDispatcher(roads) if self.role == Role::Unidentified => {
+                self.role = Role::Dispatcher(roads.clone());
+            }
+            _ => {}
+        }
+        let captured = CapturedMessage {
+            connection: self.connection,
+            role: self.role.clone(),
+            micros: self.capture.start.elapsed().as_micros() as u64,
+            message: message.clone(),
+        };
+        if let Err(e) = self.capture.record(&captured) {
+            tracing::error!("Failed to capture {captured:?}: {e:#}");
+        }
+    }
+}
+
+/// Reads a capture written by [`Capture`], in the order the messages arrived.
+pub fn read_capture(path: impl AsRef<Path>) -> anyhow::Result<Vec<CapturedMessage>> {
+    crate::jsonl::read(path, "capture")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use speedd_codecs::{camera::Camera, plate::PlateRecord};
+    use std::time::Duration;
+
+    #[tokio::test(start_paused = true)]
+    async fn records_connections_with_roles() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("capture.jsonl");
+        let capture = Capture::create(&path).unwrap();
+        let camera = Camera {
+            road: 66,
+            mile: 100,
+            limit: 60,
+        };
+        let plate = Message::Plate(PlateRecord {
+            plate: "UN1X".to_string(),
+            timestamp: 1000,
+        });
+        let (mut first, mut second) = (capture.connection(0), capture.connection(1));
+        first.record(&Message::WantHeartbeat(Duration::from_millis(2500)));
+        tokio::time::advance(Duration::from_millis(5)).await;
+        second.record(&Message::IAmDispatcher(vec![66]));
+        first.record(&Message::IAmCamera(camera.clone()));
+        tokio::time::advance(Duration::from_secs(1)).await;
+        first.record(&plate);
+        // Waits for the capture to be written
+        drop((capture, first, second));
+
+        let messages = read_capture(&path)
+            .unwrap()
+            .into_iter()
+            .map(|m| (m.connection, m.role, m.micros, m.message))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            messages,
+            [
+                (
+                    0,
+                    Role::Unidentified,
+                    0,
+                    Message::WantHeartbeat(Duration::from_millis(2500))
+                ),
+                (
+                    1,
+                    Role::Dispatcher(vec![66]),
+                    5000,
+                    Message::IAmDispatcher(vec![66])
+                ),
+                (
+                    0,
+                    Role::Camera(camera.clone()),
+                    5000,
+                    Message::IAmCamera(camera.clone())
+                ),
+                (0, Role::Camera(camera), 1_005_000, plate),
+            ]
+        );
+    }
+}
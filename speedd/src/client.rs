@@ -1,6 +1,5 @@
-use crate::{heartbeat, server};
-use speedd_codecs::{camera::Camera, client::Message};
-use tokio::sync::mpsc;
+use crate::heartbeat::Heartbeat;
+use speedd_codecs::{camera::Camera, client::Message, server};
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Action {
@@ -10,27 +9,19 @@ pub enum Action {
     SpawnDispatcher(Vec<u16>),
 }
 
-pub fn action(msg: Message, heartbeat_sender: &mut Option<mpsc::Sender<()>>) -> Action {
+pub fn action(msg: Message, heartbeat: &mut Heartbeat) -> Action {
     match msg {
         Message::Plate(record) => {
             tracing::warn!("Ignoring {record:?} due to client not having specialized as camera");
             Action::Error(server::Message::Error("You are no camera".to_string()))
         }
-        Message::WantHeartbeat(dur) => {
-            if let Some(heartbeat_sender) = heartbeat_sender.take() {
-                if dur.is_zero() {
-                    tracing::warn!("Ignoring zero-duration heartbeat");
-                } else {
-                    tracing::info!("Spawning a new heartbeat");
-                    tokio::spawn(heartbeat::run(dur, heartbeat_sender));
-                }
-                Action::None
-            } else {
-                tracing::warn!("Ignoring repeated heartbeat request");
-                Action::Error(server::Message::Error(
-                    "You already specified a heartbeat".to_string(),
-                ))
-            }
+        Message::WantHeartbeat(dur) => match heartbeat.request(dur) {
+            Ok(()) => Action::None,
+            Err(error) => Action::Error(server::Message::Error(error.to_string())),
+        },
+        Message::WantAcks | Message::Ack(_) => {
+            tracing::warn!("Ignoring {msg:?} due to client not having specialized as dispatcher");
+            Action::Error(server::Message::Error("You are no dispatcher".to_string()))
         }
         Message::IAmCamera(c) => Action::SpawnCamera(c),
         Message::IAmDispatcher(roads) => Action::SpawnDispatcher(roads),
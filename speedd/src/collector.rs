@@ -1,11 +1,81 @@
+use crate::{
+    audit::{AuditEntry, AuditLog, Outcome},
+    fines::{Ledger, LedgerEntry, Tariff},
+    metrics::METRICS,
+    persistence::{Observation, PendingTickets, SnapshotRef, Storage},
+    plates::PlateRules,
+    policy::{Rounded, ViolationPolicy},
+    sinks::Sinks,
+    spill::SpillQueue,
+    trajectory::Trajectory,
+};
 use async_channel as mpmc;
 use itertools::Itertools;
+use serde::{Deserialize, Serialize};
 use speedd_codecs::{
     camera::Camera, plate::PlateRecord, server::TicketRecord, Limit, Mile, Road, Timestamp,
     SECONDS_PER_DAY,
 };
-use std::collections::{BTreeMap, HashMap, HashSet};
-use tokio::sync::{mpsc, oneshot};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    sync::{mpsc, oneshot},
+    time::Instant,
+};
+
+/// How often to snapshot the collector state, if anything was journaled in the meantime.
+const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Journal length after which a snapshot is taken regardless of [`SNAPSHOT_INTERVAL`].
+const SNAPSHOT_JOURNAL_LIMIT: usize = 10_000;
+
+/// How often to sync the journal to disk by default, if anything was journaled in the meantime.
+pub const JOURNAL_SYNC_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Days an observation may be ahead of the newest one and still move the retention window forward.
+const MAX_DAYS_AHEAD: u32 = 7;
+
+/// Cameras which need to report from further ahead than [`MAX_DAYS_AHEAD`] before the retention
+/// window follows them, so one camera with a wrong clock cannot make the collector forget everything.
+const JUMP_QUORUM: usize = 3;
+
+/// How often to move tickets from the backlogs into the road queues while dispatchers pick them up.
+/// The interval doubles up to [`MAX_REFILL_INTERVAL`] while none are picked up.
+const REFILL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Longest wait for moving tickets into the road queues, while their dispatchers are busy or gone.
+const MAX_REFILL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Questions the admin interface can ask the collector, and the request to shut down.
+/// Each carries the channel for the answer.
+#[derive(Debug)]
+pub enum Query {
+    Roads(oneshot::Sender<Vec<RoadStatus>>),
+    TicketedDays(oneshot::Sender<BTreeMap<String, BTreeSet<u32>>>),
+    Plate(String, oneshot::Sender<Option<BTreeSet<u32>>>),
+    /// The ticket history of a plate
+    Ledger(String, oneshot::Sender<Option<Ledger>>),
+    /// Where a plate was seen across all roads, as far as the records reach back
+    Trajectory(String, oneshot::Sender<Option<Trajectory>>),
+    /// Stop for good, answering with the tickets which were not delivered
+    Shutdown(oneshot::Sender<Vec<TicketRecord>>),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct RoadStatus {
+    pub road: Road,
+    /// Speed limit in mph, as announced by the last camera which reported on this road
+    pub limit: Option<Limit>,
+    /// Tickets waiting for a dispatcher
+    pub queued_tickets: usize,
+    /// Tickets of `queued_tickets` which were spilled to disk
+    pub spilled_tickets: usize,
+    pub dispatchers: usize,
+}
 
 /// Keeps records of the samples taken, such as observed speed measurements, ticketed days, and speed limits.
 /// Manages a map of roads to mpmc (tx, rx) pairs which transport dispatched tickets.
@@ -13,12 +83,113 @@ use tokio::sync::{mpsc, oneshot};
 /// The tx is used to dispatch tickets, making use of the work-stealing behaviour of the mpmc channel:
 /// if there are no dispatchers for a given road, the mpmc channel acts as a temporary queue, and
 /// if there are one or more registered dispatchers, only one of them gets the ticket.
-#[derive(Debug, Default)]
+/// Tickets which do not fit into a road's channel wait in its backlog, which spills to disk beyond a
+/// threshold, and move on as the dispatchers make room. Camera reports never wait for dispatchers.
+/// With [`Storage`] attached, observations are journaled and the state is snapshotted periodically.
+/// With a retention window, observations and ticketed days older than that many days before the
+/// newest observation are forgotten, and observations arriving that late are ignored. Observations
+/// far ahead of the others are recorded, but only move the window once several cameras agree.
+/// With an [`AuditLog`] attached, every issued and suppressed ticket is recorded there.
+/// With [`Sinks`] attached, every issued ticket is forwarded to them.
+/// Plates are kept by their normalized form, so differing reads of a plate share a history, while
+/// tickets carry the first read of the plate.
+#[derive(Debug)]
 pub struct Collector {
-    records: HashMap<String, HashMap<Road, BTreeMap<Timestamp, Mile>>>,
+    records: HashMap<String, HashMap<Road, BTreeMap<Timestamp, Sighting>>>,
+    /// First read of each normalized plate, for the tickets. Kept as long as the plate's records
+    /// or ticketed days are
+    spellings: HashMap<String, String>,
+    plates: PlateRules,
     ticketed_days: HashMap<String, HashSet<u32>>,
+    /// Tickets issued per plate, with their fines. Kept regardless of the retention window
+    ledger: HashMap<String, Vec<LedgerEntry>>,
+    tariff: Tariff,
+    /// Speed limits in mph
     limits: HashMap<Road, Limit>,
-    dispatchers: HashMap<Road, (mpmc::Sender<TicketRecord>, mpmc::Receiver<TicketRecord>)>,
+    dispatchers: HashMap<Road, TicketQueue>,
+    /// Roads with tickets in their backlog
+    backlogged: HashSet<Road>,
+    storage: Option<Storage>,
+    /// Time between syncs of the journal, zero to sync every observation
+    journal_sync: Duration,
+    audit: Option<AuditLog>,
+    sinks: Sinks,
+    policy: Arc<dyn ViolationPolicy>,
+    ticket_queue_capacity: usize,
+    /// Tickets per road kept in memory beyond the channel before spilling to disk
+    spill_threshold: usize,
+    spill_dir: PathBuf,
+    seconds_per_day: u32,
+    retention_days: Option<u32>,
+    /// Day of the newest observation seen so far, not counting unconfirmed jumps
+    newest_day: u32,
+    /// Earliest day each camera reported from beyond [`MAX_DAYS_AHEAD`] of `newest_day`
+    jumps: HashMap<(Road, Mile), u32>,
+}
+
+/// An observation as the collector keeps it, under its plate, road and timestamp.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Sighting {
+    pub mile: Mile,
+    /// Speed limit the camera reported, in mph
+    pub limit: Limit,
+    /// The plate as the camera read it
+    pub plate: String,
+}
+
+impl Sighting {
+    fn observation(&self, road: Road, timestamp: Timestamp) -> Observation {
+        let record = PlateRecord {
+            plate: self.plate.clone(),
+            timestamp,
+        };
+        let camera = Camera {
+            road,
+            mile: self.mile,
+            limit: self.limit,
+        };
+        (record, camera)
+    }
+}
+
+/// A ticket together with the two observations it was computed from.
+type Violation = (TicketRecord, [Observation; 2]);
+
+/// The tickets of a road. Dispatchers take them from the mpmc channel, and those which did not fit
+/// into it wait in the backlog.
+#[derive(Debug)]
+struct TicketQueue {
+    tx: mpmc::Sender<TicketRecord>,
+    rx: mpmc::Receiver<TicketRecord>,
+    backlog: SpillQueue,
+}
+
+impl Default for Collector {
+    fn default() -> Self {
+        Self {
+            records: HashMap::default(),
+            spellings: HashMap::default(),
+            plates: PlateRules::default(),
+            ticketed_days: HashMap::default(),
+            ledger: HashMap::default(),
+            tariff: Tariff::default(),
+            limits: HashMap::default(),
+            dispatchers: HashMap::default(),
+            backlogged: HashSet::default(),
+            storage: None,
+            journal_sync: JOURNAL_SYNC_INTERVAL,
+            audit: None,
+            sinks: Sinks::default(),
+            policy: Arc::new(Rounded),
+            ticket_queue_capacity: 1024,
+            spill_threshold: 10_000,
+            spill_dir: std::env::temp_dir(),
+            seconds_per_day: SECONDS_PER_DAY,
+            retention_days: None,
+            newest_day: 0,
+            jumps: HashMap::default(),
+        }
+    }
 }
 
 impl Collector {
@@ -26,6 +197,115 @@ impl Collector {
         Self::default()
     }
 
+    /// Capacity of each road's ticket queue.
+    pub fn with_ticket_queue_capacity(mut self, capacity: usize) -> Self {
+        self.ticket_queue_capacity = capacity;
+        self
+    }
+
+    /// Keeps up to `threshold` tickets per road in memory beyond the queue capacity, and spills
+    /// the rest to files in `dir`. By default, 10000 tickets are kept and the system's temporary
+    /// directory is used. Set this before [`Self::with_storage`], which may queue tickets.
+    pub fn with_spilling(mut self, threshold: usize, dir: impl Into<PathBuf>) -> Self {
+        self.spill_threshold = threshold;
+        self.spill_dir = dir.into();
+        self
+    }
+
+    /// Length of the days within which a plate is ticketed at most once.
+    pub fn with_seconds_per_day(mut self, seconds_per_day: u32) -> Self {
+        self.seconds_per_day = seconds_per_day;
+        self
+    }
+
+    /// Number of days before the newest observation to keep observations and ticketed days for.
+    /// Everything is kept by default.
+    pub fn with_retention_days(mut self, days: u32) -> Self {
+        self.retention_days = Some(days);
+        self
+    }
+
+    /// Fines the tickets in the ledger. Set this before [`Self::with_storage`], so tickets from
+    /// replaying the journal are fined too. No fines by default.
+    pub fn with_tariff(mut self, tariff: Tariff) -> Self {
+        self.tariff = tariff;
+        self
+    }
+
+    /// Normalizes the plates of the observations. Set this before [`Self::with_storage`], so the
+    /// journal is replayed under the same rules. By default, plates are kept as read.
+    pub fn with_plate_rules(mut self, plates: PlateRules) -> Self {
+        self.plates = plates;
+        self
+    }
+
+    /// Decides which observations of a plate on a road make a ticket. [`Rounded`] by default.
+    pub fn with_policy(mut self, policy: Arc<dyn ViolationPolicy>) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Records the fate of every violation from now on. Attach it after [`Self::with_storage`],
+    /// so replaying the journal does not record the same tickets again.
+    pub fn with_audit_log(mut self, audit: AuditLog) -> Self {
+        self.audit = Some(audit);
+        self
+    }
+
+    /// Forwards every ticket issued from now on. Like the audit log, attach them after
+    /// [`Self::with_storage`], so replaying the journal does not forward the same tickets again.
+    pub fn with_sinks(mut self, sinks: Sinks) -> Self {
+        self.sinks = sinks;
+        self
+    }
+
+    /// Syncs the journal to disk at most `interval` after an observation was journaled, or right
+    /// away with a zero interval. Observations not yet synced are lost if the machine crashes, but
+    /// not if only speedd does. Every [`JOURNAL_SYNC_INTERVAL`] by default.
+    pub fn with_journal_sync(mut self, interval: Duration) -> Self {
+        self.journal_sync = interval;
+        self
+    }
+
+    /// Restores the collector from the snapshot and journal in `dir`, then keeps persisting there.
+    /// Tickets which were still queued at the time of the snapshot, and tickets resulting from
+    /// replaying the journal, are queued again. Tickets which were delivered after the
+    /// last snapshot may therefore be delivered twice, but none are lost, unless the machine
+    /// crashed before the journal was synced, see [`Self::with_journal_sync`].
+    pub fn with_storage(mut self, dir: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let (storage, snapshot, journal) = Storage::open(dir)?;
+        self.records = snapshot.records;
+        self.ticketed_days = snapshot.ticketed_days;
+        self.ledger = snapshot.ledger;
+        self.spellings = snapshot.spellings;
+        self.limits = snapshot.limits;
+        self.newest_day = snapshot.newest_day;
+        let observations = self
+            .records
+            .values()
+            .flat_map(HashMap::values)
+            .map(BTreeMap::len)
+            .sum::<usize>();
+        let days = self.ticketed_days.values().map(HashSet::len).sum::<usize>();
+        METRICS.retained_observations.add(observations as i64);
+        METRICS.retained_ticketed_days.add(days as i64);
+        self.prune();
+        let mut pending = snapshot.pending;
+        for (record, camera) in journal {
+            if let Some(ticket) = self.observe(record, camera)? {
+                pending.entry(ticket.road).or_default().push(ticket);
+            }
+        }
+        for (road, tickets) in pending {
+            tracing::info!("Requeueing {} tickets for road {road}", tickets.len());
+            for ticket in tickets {
+                self.enqueue(ticket)?;
+            }
+        }
+        self.storage = Some(storage);
+        Ok(self)
+    }
+
     pub async fn run(
         mut self,
         mut reporting: mpsc::Receiver<(PlateRecord, Camera)>,
@@ -33,14 +313,38 @@ impl Collector {
             Road,
             oneshot::Sender<mpmc::Receiver<TicketRecord>>,
         )>,
+        mut queries: mpsc::Receiver<Query>,
+        mut requeue: mpsc::UnboundedReceiver<TicketRecord>,
     ) -> anyhow::Result<()> {
         tracing::info!("Starting Collector loop");
+        let mut snapshot_interval = tokio::time::interval(SNAPSHOT_INTERVAL);
+        // Only ticks while something is unsynced, which never lasts with a zero interval
+        let mut journal_sync =
+            tokio::time::interval(self.journal_sync.max(Duration::from_millis(1)));
+        let mut refill_interval = REFILL_INTERVAL;
+        let refill = tokio::time::sleep(refill_interval);
+        tokio::pin!(refill);
         loop {
+            let inputs_open = !(reporting.is_closed()
+                && dispatcher_subscription.is_closed()
+                && queries.is_closed()
+                && requeue.is_closed());
             tokio::select! {
                 Some((record, camera)) = reporting.recv() => {
                     tracing::info!("{camera:?} reports {record:?}");
-                    let tickets = self.insert_record(record, camera);
-                    self.dispatch_tickets(&tickets).await?;
+                    METRICS.plates_received.inc();
+                    if let Some(storage) = &mut self.storage {
+                        storage.append(&record, &camera)?;
+                        if self.journal_sync.is_zero() {
+                            storage.sync()?;
+                        }
+                    }
+                    if let Some(ticket) = self.observe(record, camera)? {
+                        self.enqueue(ticket)?;
+                    }
+                    if self.storage.as_ref().is_some_and(|s| s.entries() >= SNAPSHOT_JOURNAL_LIMIT) {
+                        self.snapshot()?;
+                    }
                 }
                 Some((road, sender)) = dispatcher_subscription.recv() => {
                     tracing::info!("Received subscription for road {road}");
@@ -49,61 +353,340 @@ impl Collector {
                         tracing::warn!("They don't seem interested in this road anymore.");
                     }
                 }
+                Some(query) = queries.recv() => {
+                    if let Query::Shutdown(reply) = query {
+                        let tickets = self.shut_down(&mut reporting, &mut requeue)?;
+                        if let Err(tickets) = reply.send(tickets) {
+                            tracing::error!("Lost {} undelivered tickets, nobody waits for them", tickets.len());
+                        }
+                        tracing::info!("Exiting Collector loop after shutdown");
+                        return Ok(());
+                    }
+                    self.answer(query);
+                }
+                Some(ticket) = requeue.recv() => {
+                    tracing::info!("Requeueing undelivered {ticket:?}");
+                    self.enqueue(ticket)?;
+                }
+                () = &mut refill, if !self.backlogged.is_empty() && inputs_open => {}
+                _ = snapshot_interval.tick(), if self.storage.is_some() && inputs_open => {
+                    if self.storage.as_ref().is_some_and(|s| s.entries() > 0) {
+                        self.snapshot()?;
+                    }
+                }
+                _ = journal_sync.tick(), if self.storage.as_ref().is_some_and(Storage::unsynced) => {
+                    if let Some(storage) = &mut self.storage {
+                        storage.sync()?;
+                    }
+                }
                 else => break
             }
+            refill_interval = if self.refill()? > 0 {
+                REFILL_INTERVAL
+            } else {
+                (refill_interval * 2).min(MAX_REFILL_INTERVAL)
+            };
+            refill.as_mut().reset(Instant::now() + refill_interval);
             //dbg!(&self.records);
             //dbg!(&self.dispatchers.keys());
             //dbg!(&self.ticketed_days);
         }
+        self.snapshot()?;
         tracing::info!("Exiting Collector loop");
         Ok(())
     }
 
+    fn answer(&self, query: Query) {
+        let sent = match query {
+            Query::Roads(reply) => reply.send(self.road_status()).is_ok(),
+            Query::TicketedDays(reply) => {
+                let days = self
+                    .ticketed_days
+                    .iter()
+                    .map(|(plate, days)| (plate.clone(), days.iter().copied().collect()))
+                    .collect();
+                reply.send(days).is_ok()
+            }
+            Query::Plate(plate, reply) => {
+                let days = self
+                    .ticketed_days
+                    .get(&plate)
+                    .map(|days| days.iter().copied().collect());
+                reply.send(days).is_ok()
+            }
+            Query::Ledger(plate, reply) => {
+                let ledger = self
+                    .ledger
+                    .get(&plate)
+                    .map(|tickets| Ledger::new(plate, tickets.clone()));
+                reply.send(ledger).is_ok()
+            }
+            Query::Trajectory(plate, reply) => {
+                let trajectory = self
+                    .records
+                    .get(&plate)
+                    .map(|roads| Trajectory::new(plate, roads));
+                reply.send(trajectory).is_ok()
+            }
+            Query::Shutdown(_) => unreachable!("The run loop shuts down"),
+        };
+        if !sent {
+            tracing::debug!("Query was abandoned before it was answered");
+        }
+    }
+
+    fn road_status(&self) -> Vec<RoadStatus> {
+        self.limits
+            .keys()
+            .chain(self.dispatchers.keys())
+            .unique()
+            .sorted()
+            .map(|road| {
+                let (queued_tickets, spilled_tickets, dispatchers) = self
+                    .dispatchers
+                    .get(road)
+                    .map(|queue| {
+                        (
+                            queue.rx.len() + queue.backlog.len(),
+                            queue.backlog.spilled(),
+                            // The collector holds one receiver itself
+                            queue.rx.receiver_count() - 1,
+                        )
+                    })
+                    .unwrap_or_default();
+                RoadStatus {
+                    road: *road,
+                    limit: self.limits.get(road).copied(),
+                    queued_tickets,
+                    spilled_tickets,
+                    dispatchers,
+                }
+            })
+            .collect()
+    }
+
+    /// Writes the current state to storage, including the tickets still waiting in the road queues
+    /// and backlogs. The queues are drained and refilled in order. The collector is the only sender,
+    /// so they cannot fill up in between.
+    fn snapshot(&mut self) -> anyhow::Result<()> {
+        if self.storage.is_none() {
+            return Ok(());
+        }
+        let mut queued = HashMap::new();
+        for (road, queue) in &mut self.dispatchers {
+            let tickets = std::iter::from_fn(|| queue.rx.try_recv().ok()).collect_vec();
+            for ticket in &tickets {
+                queue.tx.try_send(ticket.clone())?;
+            }
+            queue.backlog.flush()?;
+            if !tickets.is_empty() || !queue.backlog.is_empty() {
+                queued.insert(*road, tickets);
+            }
+        }
+        self.write_snapshot(queued)
+    }
+
+    /// Snapshots the state with `queued` as the pending tickets, each road's followed by its backlog.
+    fn write_snapshot(&mut self, queued: HashMap<Road, Vec<TicketRecord>>) -> anyhow::Result<()> {
+        let Some(storage) = &mut self.storage else {
+            return Ok(());
+        };
+        let pending = queued
+            .into_iter()
+            .map(|(road, queued)| {
+                let backlog = self.dispatchers.get(&road).map(|queue| &queue.backlog);
+                (road, PendingTickets { queued, backlog })
+            })
+            .collect();
+        storage.snapshot(&SnapshotRef {
+            records: &self.records,
+            ticketed_days: &self.ticketed_days,
+            ledger: &self.ledger,
+            spellings: &self.spellings,
+            newest_day: self.newest_day,
+            limits: &self.limits,
+            pending,
+        })
+    }
+
+    /// Processes the plate reports and requeued tickets which are still on their way, then empties
+    /// the road queues and backlogs. Snapshots the state with these tickets as pending and returns them.
+    fn shut_down(
+        &mut self,
+        reporting: &mut mpsc::Receiver<(PlateRecord, Camera)>,
+        requeue: &mut mpsc::UnboundedReceiver<TicketRecord>,
+    ) -> anyhow::Result<Vec<TicketRecord>> {
+        let mut tickets = Vec::new();
+        for queue in self.dispatchers.values_mut() {
+            tickets.extend(std::iter::from_fn(|| queue.rx.try_recv().ok()));
+            tickets.extend(queue.backlog.tickets()?);
+        }
+        self.dispatchers.clear();
+        self.backlogged.clear();
+        while let Ok(ticket) = requeue.try_recv() {
+            tickets.push(ticket);
+        }
+        while let Ok((record, camera)) = reporting.try_recv() {
+            METRICS.plates_received.inc();
+            if let Some(storage) = &mut self.storage {
+                storage.append(&record, &camera)?;
+            }
+            tickets.extend(self.observe(record, camera)?);
+        }
+        let pending = tickets
+            .iter()
+            .cloned()
+            .into_group_map_by(|ticket| ticket.road);
+        // The backlogs are gone, their tickets are part of `tickets`
+        self.write_snapshot(pending)?;
+        Ok(tickets)
+    }
+
     pub fn insert_dispatcher(&mut self, road: u16) -> mpmc::Receiver<TicketRecord> {
-        // Create new channel for this road
-        let (_, receiver) = self
-            .dispatchers
-            .entry(road)
-            .or_insert_with(|| mpmc::bounded(1024));
-        receiver.clone()
+        self.queue(road).rx.clone()
     }
 
-    async fn dispatch_tickets(&mut self, tickets: &[TicketRecord]) -> anyhow::Result<()> {
-        for ticket in tickets {
+    /// The queue of a road, created on first use.
+    fn queue(&mut self, road: Road) -> &mut TicketQueue {
+        let (capacity, threshold) = (self.ticket_queue_capacity, self.spill_threshold);
+        let dir = &self.spill_dir;
+        self.dispatchers.entry(road).or_insert_with(|| {
+            let (tx, rx) = mpmc::bounded(capacity);
+            TicketQueue {
+                tx,
+                rx,
+                backlog: SpillQueue::new(threshold, dir.clone()),
+            }
+        })
+    }
+
+    /// Records an observation and returns the ticket to issue for it, if any.
+    pub fn observe(
+        &mut self,
+        record: PlateRecord,
+        camera: Camera,
+    ) -> anyhow::Result<Option<TicketRecord>> {
+        let key = match self.plates.normalize(&record.plate) {
+            Ok(key) => key,
+            Err(rejection) => {
+                tracing::warn!("Ignoring observation of {:?}: {rejection}", record.plate);
+                return Ok(None);
+            }
+        };
+        let violations = self.insert_record(key.clone(), record, camera);
+        self.issue_ticket(&key, violations)
+    }
+
+    /// Queues a ticket for its road, in the backlog if the channel is full or others wait there already.
+    fn enqueue(&mut self, ticket: TicketRecord) -> anyhow::Result<()> {
+        let road = ticket.road;
+        let queue = self.queue(road);
+        let ticket = if queue.backlog.is_empty() {
+            match queue.tx.try_send(ticket) {
+                Ok(()) => return Ok(()),
+                Err(mpmc::TrySendError::Full(ticket)) => ticket,
+                Err(e) => return Err(e.into()),
+            }
+        } else {
+            ticket
+        };
+        queue.backlog.push(ticket)?;
+        self.backlogged.insert(road);
+        Ok(())
+    }
+
+    /// Moves tickets from the backlogs into the channels the dispatchers made room in, returning
+    /// how many were moved.
+    fn refill(&mut self) -> anyhow::Result<usize> {
+        let mut moved = 0;
+        for road in &self.backlogged {
+            let queue = self
+                .dispatchers
+                .get_mut(road)
+                .expect("Backlogged roads have a queue");
+            while !queue.tx.is_full() {
+                let Some(ticket) = queue.backlog.pop()? else {
+                    break;
+                };
+                queue.tx.try_send(ticket)?;
+                moved += 1;
+            }
+        }
+        self.backlogged
+            .retain(|road| !self.dispatchers[road].backlog.is_empty());
+        Ok(moved)
+    }
+
+    /// Picks the first ticket which does not cover an already ticketed day, and marks its days as
+    /// ticketed. `key` is the normalized plate of the tickets.
+    fn issue_ticket(
+        &mut self,
+        key: &str,
+        violations: Vec<Violation>,
+    ) -> anyhow::Result<Option<TicketRecord>> {
+        let seconds_per_day = self.seconds_per_day;
+        for (ticket, observations) in violations {
             tracing::info!("Violation found: {ticket:?}");
-            let ticketed_days = self.ticketed_days.entry(ticket.plate.clone()).or_default();
-            if Self::days(ticket.timestamp1, ticket.timestamp2)
-                .any(|day| ticketed_days.contains(&day))
-            {
-                let day = Self::day(ticket.timestamp1);
+            let ticketed_days = self.ticketed_days.entry(key.to_string()).or_default();
+            let suppressed = Self::days(seconds_per_day, ticket.timestamp1, ticket.timestamp2)
+                .any(|day| ticketed_days.contains(&day));
+            if let Some(audit) = &self.audit {
+                let outcome = if suppressed {
+                    Outcome::Suppressed
+                } else {
+                    Outcome::Issued
+                };
+                audit.record(&AuditEntry::new(outcome, &ticket, observations))?;
+            }
+            if suppressed {
+                let day = Self::day(seconds_per_day, ticket.timestamp1);
                 tracing::info!("Ignoring ticket starting on day {day}: {ticket:?}");
+                METRICS.tickets_suppressed.inc();
             } else {
-                for day in Self::days(ticket.timestamp1, ticket.timestamp2) {
-                    ticketed_days.insert(day);
+                for day in Self::days(seconds_per_day, ticket.timestamp1, ticket.timestamp2) {
+                    if ticketed_days.insert(day) {
+                        METRICS.retained_ticketed_days.inc();
+                    }
                 }
-                // find mpmc sender for this road, or create and register one
-                let (tx, _) = self
-                    .dispatchers
-                    .entry(ticket.road)
-                    .or_insert_with(|| mpmc::bounded(1024));
-                tx.send(ticket.clone()).await?;
-                break;
+                METRICS.tickets_generated.inc();
+                let limit = self.limits.get(&ticket.road).copied().unwrap_or_default();
+                self.ledger
+                    .entry(key.to_string())
+                    .or_default()
+                    .push(LedgerEntry::new(&ticket, limit, &self.tariff));
+                self.sinks.forward(&ticket);
+                return Ok(Some(ticket));
             }
         }
-        Ok(())
+        Ok(None)
     }
 
+    /// Records an observation under the normalized plate `key`, returning the violations it completes.
     fn insert_record(
         &mut self,
+        key: String,
         PlateRecord { plate, timestamp }: PlateRecord,
         Camera { road, mile, limit }: Camera,
-    ) -> Vec<TicketRecord> {
-        let limit = limit.saturating_mul(100);
+    ) -> Vec<Violation> {
         self.limits.insert(road, limit);
 
+        let day = Self::day(self.seconds_per_day, timestamp);
+        if self.retention_start().is_some_and(|start| day < start) {
+            tracing::debug!("Ignoring observation of {plate} at {timestamp}, it is past retention");
+            return Vec::new();
+        }
+        if day > self.newest_day {
+            self.advance(day, (road, mile));
+        }
+
+        let spelling = self
+            .spellings
+            .entry(key.clone())
+            .or_insert_with(|| plate.clone())
+            .clone();
         let map = self
             .records
-            .entry(plate.to_string())
+            .entry(key)
             .or_default()
             .entry(road)
             .or_default();
@@ -111,69 +694,144 @@ impl Collector {
         let prev = map
             .range(..timestamp)
             .next_back()
-            .map(|(ts, mile)| (*ts, *mile));
-        let next = map.range(timestamp..).next().map(|(ts, mile)| (*ts, *mile));
+            .map(|(ts, sighting)| (*ts, sighting.clone()));
+        let next = map
+            .range(timestamp..)
+            .next()
+            .map(|(ts, sighting)| (*ts, sighting.clone()));
 
-        *map.entry(timestamp).or_default() = mile;
+        let sighting = Sighting { mile, limit, plate };
+        let observation = sighting.observation(road, timestamp);
+        if map.insert(timestamp, sighting).is_none() {
+            METRICS.retained_observations.inc();
+        }
 
-        let mut tickets: Vec<TicketRecord> = Vec::new();
+        let mut violations: Vec<Violation> = Vec::new();
 
-        if let Some((earlier, previous_mile)) = prev {
-            if let Some(speed) = Self::is_violation(limit, earlier, timestamp, previous_mile, mile)
+        if let Some((earlier, previous)) = prev {
+            if let Some(speed) =
+                self.policy
+                    .violation(road, limit, (earlier, previous.mile), (timestamp, mile))
             {
-                tickets.push(TicketRecord {
-                    plate: plate.clone(),
+                let ticket = TicketRecord {
+                    plate: spelling.clone(),
                     road,
-                    mile1: previous_mile,
+                    mile1: previous.mile,
                     timestamp1: earlier,
                     mile2: mile,
                     timestamp2: timestamp,
                     speed,
-                });
+                };
+                let observations = [previous.observation(road, earlier), observation.clone()];
+                violations.push((ticket, observations));
             }
         }
-        if let Some((later, next_mile)) = next {
-            if let Some(speed) = Self::is_violation(limit, timestamp, later, mile, next_mile) {
-                tickets.push(TicketRecord {
-                    plate,
+        if let Some((later, next)) = next {
+            if let Some(speed) =
+                self.policy
+                    .violation(road, limit, (timestamp, mile), (later, next.mile))
+            {
+                let ticket = TicketRecord {
+                    plate: spelling,
                     road,
                     mile1: mile,
                     timestamp1: timestamp,
-                    mile2: next_mile,
+                    mile2: next.mile,
                     timestamp2: later,
                     speed,
-                });
+                };
+                violations.push((ticket, [observation, next.observation(road, later)]));
             }
         }
 
-        tickets
+        violations
     }
 
-    fn is_violation(limit: u16, ts1: u32, ts2: u32, mile1: u16, mile2: u16) -> Option<u16> {
-        let delta_t = ts1.abs_diff(ts2);
-        let delta_m = mile1.abs_diff(mile2);
-        let speed = (delta_m as f32 / delta_t as f32) * 60.0 * 60.0;
-        let speed = speed.round() as u16;
-        let speed = speed.saturating_mul(100);
-        if speed > limit {
-            Some(speed)
+    /// Moves the retention window forward to `day`, reported by the camera at `position`, unless
+    /// that is too far ahead and not enough other cameras are there as well.
+    fn advance(&mut self, day: u32, position: (Road, Mile)) {
+        let limit = self.newest_day.saturating_add(MAX_DAYS_AHEAD);
+        if day > limit && !self.records.is_empty() {
+            let earliest = self.jumps.entry(position).or_insert(day);
+            *earliest = day.min(*earliest);
+            if self.jumps.len() < JUMP_QUORUM {
+                tracing::warn!(
+                    "Camera at mile {} of road {} reports day {day}, newest is day {}. Not moving the retention window",
+                    position.1,
+                    position.0,
+                    self.newest_day
+                );
+                return;
+            }
+            // Enough cameras agree that time moved on, as far as the least ahead of them says
+            let day = self.jumps.values().copied().min().unwrap_or(day);
+            tracing::info!("{} cameras moved on to day {day}", self.jumps.len());
+            self.jumps.clear();
+            self.newest_day = day;
         } else {
-            None
+            self.newest_day = day;
+            let limit = self.newest_day.saturating_add(MAX_DAYS_AHEAD);
+            self.jumps.retain(|_, earliest| *earliest > limit);
+        }
+        self.prune();
+    }
+
+    /// First day which is retained, if there is a retention window.
+    fn retention_start(&self) -> Option<u32> {
+        self.retention_days
+            .map(|days| self.newest_day.saturating_sub(days))
+    }
+
+    /// Forgets observations and ticketed days from before the retention window.
+    fn prune(&mut self) {
+        let Some(start) = self.retention_start() else {
+            return;
+        };
+        let cutoff = start.saturating_mul(self.seconds_per_day);
+        let mut observations = 0;
+        self.records.retain(|_, roads| {
+            roads.retain(|_, timestamps| {
+                let retained = timestamps.split_off(&cutoff);
+                observations += timestamps.len();
+                *timestamps = retained;
+                !timestamps.is_empty()
+            });
+            !roads.is_empty()
+        });
+        let mut days = 0;
+        self.ticketed_days.retain(|_, ticketed| {
+            let before = ticketed.len();
+            ticketed.retain(|day| *day >= start);
+            days += before - ticketed.len();
+            !ticketed.is_empty()
+        });
+        self.spellings.retain(|key, _| {
+            self.records.contains_key(key) || self.ticketed_days.contains_key(key)
+        });
+        if observations > 0 || days > 0 {
+            tracing::info!(
+                "Pruned {observations} observations and {days} ticketed days before day {start}"
+            );
         }
+        METRICS.retained_observations.sub(observations as i64);
+        METRICS.retained_ticketed_days.sub(days as i64);
     }
 
-    fn days(timestamp1: u32, timestamp2: u32) -> impl Iterator<Item = u32> {
-        (timestamp1..timestamp2).map(Self::day).unique()
+    fn days(seconds_per_day: u32, timestamp1: u32, timestamp2: u32) -> impl Iterator<Item = u32> {
+        (timestamp1..timestamp2)
+            .map(move |timestamp| Self::day(seconds_per_day, timestamp))
+            .unique()
     }
 
-    fn day(timestamp: u32) -> u32 {
-        f32::floor(timestamp as f32 / SECONDS_PER_DAY as f32) as u32
+    fn day(seconds_per_day: u32, timestamp: u32) -> u32 {
+        f32::floor(timestamp as f32 / seconds_per_day as f32) as u32
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::config;
 
     #[tokio::test]
     async fn example() {
@@ -227,8 +885,10 @@ mod test {
         drop(disp_tx);
         drop(sender);
 
+        let (_, queries) = mpsc::channel(1);
+        let (_, requeue) = mpsc::unbounded_channel();
         let col = Collector::new();
-        col.run(receiver, disp_rx).await.unwrap();
+        col.run(receiver, disp_rx, queries, requeue).await.unwrap();
         let ticket_rx = rx.await.unwrap();
         let val = ticket_rx.recv().await.unwrap();
         assert_eq!(
@@ -244,4 +904,219 @@ mod test {
             }
         );
     }
+
+    fn observation(plate: &str, timestamp: u32, road: u16, mile: u16) -> (PlateRecord, Camera) {
+        (
+            PlateRecord {
+                plate: plate.to_string(),
+                timestamp,
+            },
+            Camera {
+                road,
+                mile,
+                limit: 10,
+            },
+        )
+    }
+
+    #[tokio::test]
+    async fn restores_queued_tickets_and_ticketed_days() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let (sender, receiver) = mpsc::channel(2);
+        sender.send(observation("ABC", 1, 12, 2)).await.unwrap();
+        sender.send(observation("ABC", 20, 12, 4)).await.unwrap();
+        drop(sender);
+        let (_disp_tx, disp_rx) = mpsc::channel(1);
+        let (_, queries) = mpsc::channel(1);
+        let (_, requeue) = mpsc::unbounded_channel();
+        let col = Collector::new().with_storage(dir.path()).unwrap();
+        tokio::time::timeout(
+            Duration::from_millis(100),
+            col.run(receiver, disp_rx, queries, requeue),
+        )
+        .await
+        .unwrap_err();
+
+        // Restart: the undelivered ticket is queued again, and the day stays ticketed
+        let (sender, receiver) = mpsc::channel(1);
+        sender.send(observation("ABC", 40, 12, 8)).await.unwrap();
+        drop(sender);
+        let (disp_tx, disp_rx) = mpsc::channel(1);
+        let (tx, rx) = oneshot::channel();
+        disp_tx.send((12, tx)).await.unwrap();
+        drop(disp_tx);
+
+        let (_, queries) = mpsc::channel(1);
+        let (_, requeue) = mpsc::unbounded_channel();
+        let col = Collector::new().with_storage(dir.path()).unwrap();
+        col.run(receiver, disp_rx, queries, requeue).await.unwrap();
+        let ticket_rx = rx.await.unwrap();
+        let ticket = ticket_rx.try_recv().unwrap();
+        assert_eq!((ticket.timestamp1, ticket.timestamp2), (1, 20));
+        assert!(ticket_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn replays_journal() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let (mut storage, _, _) = Storage::open(dir.path()).unwrap();
+            for (record, camera) in [observation("ABC", 1, 12, 2), observation("ABC", 20, 12, 4)] {
+                storage.append(&record, &camera).unwrap();
+            }
+            assert!(storage.unsynced());
+            storage.sync().unwrap();
+            assert!(!storage.unsynced());
+        }
+
+        let mut col = Collector::new()
+            .with_tariff(Tariff::new(vec![config::Bracket { over: 0, fine: 50 }]))
+            .with_storage(dir.path())
+            .unwrap();
+        assert_eq!(col.ledger["ABC"][0].fine, 50);
+        let ticket_rx = col.insert_dispatcher(12);
+        let ticket = ticket_rx.try_recv().unwrap();
+        assert_eq!(ticket.speed, 37900);
+    }
+
+    #[tokio::test]
+    async fn unattended_road_spills_to_disk() {
+        let dir = tempfile::tempdir().unwrap();
+        let (sender, receiver) = mpsc::channel(16);
+        let (disp_tx, disp_rx) = mpsc::channel(1);
+        let (query_tx, queries) = mpsc::channel(1);
+        let (_requeue_tx, requeue) = mpsc::unbounded_channel();
+        let col = Collector::new()
+            .with_ticket_queue_capacity(100)
+            .with_spilling(1000, dir.path());
+        let collector = tokio::spawn(col.run(receiver, disp_rx, queries, requeue));
+
+        // Far more tickets than fit into the road queue, without stalling the reports
+        let plates = (0..10_000).map(|n| format!("P{n}")).collect_vec();
+        for plate in &plates {
+            sender.send(observation(plate, 0, 12, 0)).await.unwrap();
+            sender.send(observation(plate, 360, 12, 10)).await.unwrap();
+        }
+        let roads = loop {
+            let (tx, rx) = oneshot::channel();
+            query_tx.send(Query::Roads(tx)).await.unwrap();
+            let roads = rx.await.unwrap();
+            if roads[0].queued_tickets == plates.len() {
+                break roads;
+            }
+        };
+        assert_eq!(roads[0].spilled_tickets, 10_000 - 100 - 1000);
+
+        // A dispatcher gets them all, in order
+        let (tx, rx) = oneshot::channel();
+        disp_tx.send((12, tx)).await.unwrap();
+        let ticket_rx = rx.await.unwrap();
+        for plate in &plates {
+            assert_eq!(&ticket_rx.recv().await.unwrap().plate, plate);
+        }
+        assert!(ticket_rx.is_empty());
+
+        let (tx, rx) = oneshot::channel();
+        query_tx.send(Query::Shutdown(tx)).await.unwrap();
+        assert!(rx.await.unwrap().is_empty());
+        collector.await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn snapshot_keeps_spilled_tickets() {
+        let (storage, spill) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let mut col = Collector::new()
+            .with_ticket_queue_capacity(2)
+            .with_spilling(3, spill.path())
+            .with_storage(storage.path())
+            .unwrap();
+        let plates = (0..20).map(|n| format!("P{n}")).collect_vec();
+        for plate in &plates {
+            for (record, camera) in [
+                observation(plate, 0, 12, 0),
+                observation(plate, 360, 12, 10),
+            ] {
+                if let Some(ticket) = col.observe(record, camera).unwrap() {
+                    col.enqueue(ticket).unwrap();
+                }
+            }
+        }
+        assert_eq!(col.dispatchers[&12].backlog.spilled(), 20 - 2 - 3);
+        col.snapshot().unwrap();
+        drop(col);
+
+        let col = Collector::new()
+            .with_spilling(3, spill.path())
+            .with_storage(storage.path())
+            .unwrap();
+        let queue = &col.dispatchers[&12];
+        let restored = std::iter::from_fn(|| queue.rx.try_recv().ok())
+            .map(|ticket| ticket.plate)
+            .collect_vec();
+        assert_eq!(restored, plates);
+    }
+
+    #[test]
+    fn prunes_outside_retention_window() {
+        let mut col = Collector::new()
+            .with_seconds_per_day(100)
+            .with_retention_days(2);
+        for (record, camera) in [observation("ABC", 10, 12, 2), observation("ABC", 20, 12, 4)] {
+            col.observe(record, camera).unwrap();
+        }
+        assert_eq!(col.ticketed_days["ABC"], HashSet::from([0]));
+        let (record, camera) = observation("DEF", 150, 12, 2);
+        col.observe(record, camera).unwrap();
+        assert_eq!(col.records.len(), 2);
+
+        // Day 3 starts the window at day 1, so everything from day 0 is forgotten
+        let (record, camera) = observation("DEF", 310, 12, 2);
+        assert!(col.observe(record, camera).unwrap().is_none());
+        assert_eq!(col.records.keys().collect_vec(), ["DEF"]);
+        assert!(col.ticketed_days.is_empty());
+
+        // Too late to be considered
+        let (record, camera) = observation("ABC", 30, 12, 6);
+        assert!(col.observe(record, camera).unwrap().is_none());
+        assert!(!col.records.contains_key("ABC"));
+    }
+
+    #[test]
+    fn one_camera_far_ahead_does_not_move_retention_window() {
+        let mut col = Collector::new()
+            .with_seconds_per_day(100)
+            .with_retention_days(2);
+        for (record, camera) in [observation("ABC", 10, 12, 2), observation("ABC", 20, 12, 4)] {
+            col.observe(record, camera).unwrap();
+        }
+
+        // A camera with a clock years ahead, reporting over and over
+        for timestamp in [100_000, 100_010, 100_020] {
+            let (record, camera) = observation("XYZ", timestamp, 12, 6);
+            col.observe(record, camera).unwrap();
+        }
+        assert_eq!(col.newest_day, 0);
+        assert!(col.records.contains_key("ABC"));
+
+        // Normal traffic carries on
+        let (record, camera) = observation("DEF", 150, 12, 2);
+        col.observe(record, camera).unwrap();
+        let (record, camera) = observation("DEF", 160, 12, 4);
+        assert!(col.observe(record, camera).unwrap().is_some());
+        assert_eq!(col.newest_day, 1);
+        assert_eq!(col.records.len(), 3);
+
+        // Once enough cameras are there, the window follows the earliest of them
+        let (record, camera) = observation("GHI", 100_050, 12, 8);
+        col.observe(record, camera).unwrap();
+        assert_eq!(col.newest_day, 1);
+        let (record, camera) = observation("JKL", 99_950, 13, 1);
+        col.observe(record, camera).unwrap();
+        assert_eq!(col.newest_day, 999);
+        assert_eq!(
+            col.records.keys().sorted().collect_vec(),
+            ["GHI", "JKL", "XYZ"]
+        );
+    }
 }
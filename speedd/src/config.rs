@@ -0,0 +1,662 @@
+use crate::{arguments::Arguments, cameras::ConflictPolicy, collector::JOURNAL_SYNC_INTERVAL};
+use anyhow::Context;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use speedd_codecs::{Road, SECONDS_PER_DAY};
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tracing_subscriber::filter::LevelFilter;
+
+/// Server configuration, read from a TOML file. Missing entries take their default values.
+///
+/// ```toml
+/// address = "0.0.0.0:8000"
+/// storage = "/var/lib/speedd"
+/// journal_sync = 100
+/// admin = "127.0.0.1:9000"
+/// tls = { certificate = "/etc/speedd/cert.pem", key = "/etc/speedd/key.pem" }
+/// seconds_per_day = 86400
+/// retention_days = 30
+/// audit_log = "/var/log/speedd/audit.jsonl"
+/// capture = "/var/lib/speedd/capture.jsonl"
+/// camera_conflicts = "reject"
+/// strict = true
+/// shards = 8
+///
+/// [log]
+/// level = "debug"
+/// format = "full"
+///
+/// [policy]
+/// kind = "exact"
+/// tolerance = 50
+///
+/// [[policy.roads]]
+/// road = 66
+/// kind = "rounded"
+///
+/// [timeouts]
+/// identification = 10
+/// idle = 300
+///
+/// [[tariff]]
+/// over = 0
+/// fine = 50
+///
+/// [[tariff]]
+/// over = 20
+/// fine = 300
+///
+/// [[sinks]]
+/// kind = "csv"
+/// path = "/var/lib/speedd/tickets.csv"
+///
+/// [[sinks]]
+/// kind = "webhook"
+/// url = "https://backoffice.example.com/tickets"
+/// retries = 5
+/// backoff = 100
+///
+/// [health]
+/// silence = 300
+/// max_skew = 60
+///
+/// [plates]
+/// uppercase = true
+/// strip = true
+/// ocr_confusions = true
+/// min_length = 2
+/// max_length = 8
+/// alphanumeric = true
+///
+/// [shutdown]
+/// drain_timeout = 10
+/// backlog = "/var/lib/speedd/backlog.jsonl"
+///
+/// [limits]
+/// connections_per_ip = 64
+/// plates_per_second = 100
+/// plate_burst = 1000
+///
+/// [queues]
+/// reporting = 256
+/// subscriptions = 16
+/// queries = 16
+/// tickets = 1024
+/// unacked = 16
+/// sinks = 1024
+/// spill_threshold = 10000
+/// spill_dir = "/var/tmp/speedd"
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub address: SocketAddr,
+    pub storage: Option<PathBuf>,
+    /// Milliseconds between syncs of the storage journal to disk, 0 to sync every observation.
+    /// A crash of the machine loses the observations of up to that long, a crash of speedd none
+    pub journal_sync: u64,
+    pub admin: Option<SocketAddr>,
+    /// Serve TLS instead of plain TCP on `address`
+    pub tls: Option<Tls>,
+    pub seconds_per_day: u32,
+    /// Days of observations and ticketed days to keep in memory, counted back from the newest observation
+    pub retention_days: Option<u32>,
+    /// File to log every issued and suppressed ticket to
+    pub audit_log: Option<PathBuf>,
+    /// File to record every decoded client message to, for replaying the traffic later
+    pub capture: Option<PathBuf>,
+    /// What to do about cameras disagreeing on a road's limit or sharing a position
+    pub camera_conflicts: ConflictPolicy,
+    /// Whether to disconnect clients after sending them an error, as the spec demands
+    pub strict: bool,
+    /// Number of collector tasks the plates are partitioned across
+    pub shards: usize,
+    pub log: Log,
+    pub policy: Policy,
+    pub timeouts: Timeouts,
+    pub health: Health,
+    pub plates: Plates,
+    /// Fines for the tickets in the ledger, by mph over the limit
+    pub tariff: Vec<Bracket>,
+    /// Where to forward issued tickets to, besides the dispatchers
+    pub sinks: Vec<Sink>,
+    pub shutdown: Shutdown,
+    pub limits: Limits,
+    pub queues: Queues,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Log {
+    /// One of `off`, `error`, `warn`, `info`, `debug`, `trace`
+    pub level: String,
+    pub format: LogFormat,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, Deserialize, Serialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    #[default]
+    Full,
+    Compact,
+    Pretty,
+    Json,
+}
+
+/// PEM files of the server's certificate chain and private key.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Tls {
+    pub certificate: PathBuf,
+    pub key: PathBuf,
+}
+
+/// Which observations make a ticket, see [`crate::policy`].
+#[derive(Debug, Clone, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Policy {
+    pub kind: PolicyKind,
+    /// Hundredths of mph a car may exceed the limit by, for the `exact` policy
+    pub tolerance: u16,
+    /// Roads with a policy of their own
+    pub roads: Vec<RoadPolicy>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RoadPolicy {
+    pub road: Road,
+    pub kind: PolicyKind,
+    #[serde(default)]
+    pub tolerance: u16,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, Deserialize, Serialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum PolicyKind {
+    /// Speed rounded to whole mph exceeds the limit, as in the spec
+    #[default]
+    Rounded,
+    /// Exact speed exceeds the limit plus the tolerance
+    Exact,
+}
+
+/// When cameras are flagged on the admin interface and in the logs, see [`crate::cameras`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Health {
+    /// Seconds a connected camera may go without reporting a plate
+    pub silence: u64,
+    /// Seconds a camera's clock may be off the median of the cameras on its road
+    pub max_skew: u64,
+}
+
+impl Health {
+    pub fn silence(&self) -> Duration {
+        Duration::from_secs(self.silence)
+    }
+
+    pub fn max_skew(&self) -> Duration {
+        Duration::from_secs(self.max_skew)
+    }
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Self {
+            silence: 300,
+            max_skew: 60,
+        }
+    }
+}
+
+/// How plate reads are normalized and validated, see [`crate::plates`]. By default, plates are
+/// taken as they are and only empty ones are dropped. Dropped reads are logged and counted, the
+/// camera stays connected.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Plates {
+    /// Turn ASCII letters into upper case
+    pub uppercase: bool,
+    /// Drop everything but ASCII letters and digits, such as spaces and dashes
+    pub strip: bool,
+    /// Read the letters O, I and B as the digits 0, 1 and 8, which OCR confuses them with
+    pub ocr_confusions: bool,
+    /// Characters a plate needs after normalization
+    pub min_length: usize,
+    /// Characters a plate may have after normalization
+    pub max_length: Option<usize>,
+    /// Reject plates which still contain anything but ASCII letters and digits after normalization
+    pub alphanumeric: bool,
+}
+
+impl Default for Plates {
+    fn default() -> Self {
+        Self {
+            uppercase: false,
+            strip: false,
+            ocr_confusions: false,
+            min_length: 1,
+            max_length: None,
+            alphanumeric: false,
+        }
+    }
+}
+
+/// A bracket of the tariff, see [`crate::fines`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Bracket {
+    /// Whole mph over the limit from which on the fine applies
+    pub over: u16,
+    pub fine: u32,
+}
+
+/// A destination for issued tickets, see [`crate::sinks`].
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Sink {
+    /// JSONL file to append to
+    Jsonl { path: PathBuf },
+    /// CSV file to append to
+    Csv { path: PathBuf },
+    /// URL to POST every ticket to as JSON
+    Webhook {
+        url: String,
+        /// Attempts after the first failed one
+        #[serde(default = "Sink::default_retries")]
+        retries: u32,
+        /// Milliseconds to wait before the first retry, doubling with every further one
+        #[serde(default = "Sink::default_backoff")]
+        backoff: u64,
+    },
+}
+
+impl Sink {
+    fn default_retries() -> u32 {
+        5
+    }
+
+    fn default_backoff() -> u64 {
+        100
+    }
+
+    /// A webhook with the default retries.
+    pub fn webhook(url: String) -> Self {
+        Self::Webhook {
+            url,
+            retries: Self::default_retries(),
+            backoff: Self::default_backoff(),
+        }
+    }
+}
+
+/// Connection timeouts in seconds. None are enforced by default.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Timeouts {
+    /// Time a client gets to send `IAmCamera` or `IAmDispatcher`
+    pub identification: Option<u64>,
+    /// Time a camera which did not ask for heartbeats may go without sending anything
+    pub idle: Option<u64>,
+}
+
+impl Timeouts {
+    pub fn identification(&self) -> Option<Duration> {
+        self.identification.map(Duration::from_secs)
+    }
+
+    pub fn idle(&self) -> Option<Duration> {
+        self.idle.map(Duration::from_secs)
+    }
+}
+
+/// What happens to queued tickets on SIGTERM or Ctrl-C.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Shutdown {
+    /// Seconds connected dispatchers get to pick up the queued tickets
+    pub drain_timeout: u64,
+    /// JSONL file for the tickets which are still undelivered after draining, loaded again on startup.
+    /// With `storage`, they are kept in the snapshots instead
+    pub backlog: Option<PathBuf>,
+}
+
+impl Shutdown {
+    pub fn drain_timeout(&self) -> Duration {
+        Duration::from_secs(self.drain_timeout)
+    }
+
+    /// Where the backlog is moved on startup. It is kept until the next shutdown saved the tickets
+    /// still undelivered, so a crash in between loses none of them.
+    pub fn consumed_backlog(&self) -> Option<PathBuf> {
+        let mut path = self.backlog.clone()?.into_os_string();
+        path.push(".consumed");
+        Some(path.into())
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self {
+            drain_timeout: 10,
+            backlog: None,
+        }
+    }
+}
+
+/// Protection against misbehaving clients. No limits apply by default.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// Connections allowed from a single IP address at a time
+    pub connections_per_ip: Option<usize>,
+    /// Plate reports per second and camera connection, on average
+    pub plates_per_second: Option<u32>,
+    /// Plate reports a camera may send in a burst, defaults to `plates_per_second`
+    pub plate_burst: Option<u32>,
+}
+
+/// Channel capacities, and where tickets go which do not fit.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Queues {
+    /// Plate reports from all cameras into each collector shard
+    pub reporting: usize,
+    /// Dispatcher subscriptions into each collector shard
+    pub subscriptions: usize,
+    /// Admin queries into each collector shard
+    pub queries: usize,
+    /// Tickets waiting for a dispatcher, per road
+    pub tickets: usize,
+    /// Tickets a dispatcher which acknowledges may have unacknowledged before it gets more
+    pub unacked: usize,
+    /// Issued tickets waiting for each ticket sink, beyond which they are dropped for that sink
+    pub sinks: usize,
+    /// Tickets per road kept in memory beyond `tickets`, before spilling to disk
+    pub spill_threshold: usize,
+    /// Directory for the spilled tickets, the system's temporary directory by default
+    pub spill_dir: Option<PathBuf>,
+}
+
+impl Queues {
+    pub fn spill_dir(&self) -> PathBuf {
+        self.spill_dir.clone().unwrap_or_else(std::env::temp_dir)
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            address: SocketAddr::from(([0, 0, 0, 0], 8000)),
+            storage: None,
+            journal_sync: JOURNAL_SYNC_INTERVAL.as_millis() as u64,
+            admin: None,
+            tls: None,
+            seconds_per_day: SECONDS_PER_DAY,
+            retention_days: None,
+            audit_log: None,
+            capture: None,
+            camera_conflicts: ConflictPolicy::default(),
+            strict: true,
+            shards: std::thread::available_parallelism().map_or(1, usize::from),
+            log: Log::default(),
+            policy: Policy::default(),
+            timeouts: Timeouts::default(),
+            health: Health::default(),
+            plates: Plates::default(),
+            tariff: Vec::new(),
+            sinks: Vec::new(),
+            shutdown: Shutdown::default(),
+            limits: Limits::default(),
+            queues: Queues::default(),
+        }
+    }
+}
+
+impl Default for Log {
+    fn default() -> Self {
+        Self {
+            level: LevelFilter::DEBUG.to_string(),
+            format: LogFormat::default(),
+        }
+    }
+}
+
+impl Default for Queues {
+    fn default() -> Self {
+        Self {
+            reporting: 256,
+            subscriptions: 16,
+            queries: 16,
+            tickets: 1024,
+            unacked: 16,
+            sinks: 1024,
+            spill_threshold: 10_000,
+            spill_dir: None,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_file(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let config = std::fs::read_to_string(path).context("Failed to read config file")?;
+        toml::from_str(&config).context("Failed to parse config toml file")
+    }
+
+    /// Reads the configuration file named in the arguments, if any, and applies the arguments on top.
+    pub fn from_arguments(args: Arguments) -> anyhow::Result<Self> {
+        let mut config = match &args.config {
+            Some(path) => Self::from_file(path)?,
+            None => Self::default(),
+        };
+        if let Some(address) = args.address {
+            config.address = address;
+        }
+        if let Some(storage) = args.storage {
+            config.storage = Some(storage);
+        }
+        if let Some(journal_sync) = args.journal_sync {
+            config.journal_sync = journal_sync;
+        }
+        if let Some(admin) = args.admin {
+            config.admin = Some(admin);
+        }
+        match (args.tls_certificate, args.tls_key, &mut config.tls) {
+            (None, None, _) => {}
+            (Some(certificate), Some(key), tls) => *tls = Some(Tls { certificate, key }),
+            (certificate, key, Some(tls)) => {
+                tls.certificate = certificate.unwrap_or(tls.certificate.clone());
+                tls.key = key.unwrap_or(tls.key.clone());
+            }
+            (_, _, None) => anyhow::bail!("TLS needs both a certificate and a key"),
+        }
+        if let Some(level) = args.log_level {
+            config.log.level = level.to_string();
+        }
+        if let Some(format) = args.log_format {
+            config.log.format = format;
+        }
+        if let Some(seconds_per_day) = args.seconds_per_day {
+            config.seconds_per_day = seconds_per_day;
+        }
+        if let Some(retention_days) = args.retention_days {
+            config.retention_days = Some(retention_days);
+        }
+        if let Some(audit_log) = args.audit_log {
+            config.audit_log = Some(audit_log);
+        }
+        if let Some(capture) = args.capture {
+            config.capture = Some(capture);
+        }
+        if let Some(camera_conflicts) = args.camera_conflicts {
+            config.camera_conflicts = camera_conflicts;
+        }
+        if args.lenient {
+            config.strict = false;
+        }
+        if let Some(shards) = args.shards {
+            config.shards = shards;
+        }
+        if let Some(kind) = args.policy {
+            config.policy.kind = kind;
+        }
+        if let Some(tolerance) = args.tolerance {
+            config.policy.tolerance = tolerance;
+        }
+        if let Some(identification) = args.identification_timeout {
+            config.timeouts.identification = Some(identification);
+        }
+        if let Some(idle) = args.idle_timeout {
+            config.timeouts.idle = Some(idle);
+        }
+        if args.normalize_plates {
+            config.plates.uppercase = true;
+            config.plates.strip = true;
+        }
+        if args.ocr_confusions {
+            config.plates.ocr_confusions = true;
+        }
+        config.sinks.extend(
+            args.ticket_jsonl
+                .into_iter()
+                .map(|path| Sink::Jsonl { path })
+                .chain(args.ticket_csv.into_iter().map(|path| Sink::Csv { path }))
+                .chain(args.ticket_webhook.into_iter().map(Sink::webhook)),
+        );
+        if let Some(drain_timeout) = args.drain_timeout {
+            config.shutdown.drain_timeout = drain_timeout;
+        }
+        if let Some(backlog) = args.ticket_backlog {
+            config.shutdown.backlog = Some(backlog);
+        }
+        if let Some(connections) = args.connections_per_ip {
+            config.limits.connections_per_ip = Some(connections);
+        }
+        if let Some(rate) = args.plates_per_second {
+            config.limits.plates_per_second = Some(rate);
+        }
+        if let Some(burst) = args.plate_burst {
+            config.limits.plate_burst = Some(burst);
+        }
+        if let Some(reporting) = args.reporting_capacity {
+            config.queues.reporting = reporting;
+        }
+        if let Some(subscriptions) = args.subscription_capacity {
+            config.queues.subscriptions = subscriptions;
+        }
+        if let Some(tickets) = args.ticket_capacity {
+            config.queues.tickets = tickets;
+        }
+        if let Some(unacked) = args.unacked_tickets {
+            config.queues.unacked = unacked;
+        }
+        if let Some(sinks) = args.sink_capacity {
+            config.queues.sinks = sinks;
+        }
+        if let Some(threshold) = args.spill_threshold {
+            config.queues.spill_threshold = threshold;
+        }
+        if let Some(dir) = args.spill_dir {
+            config.queues.spill_dir = Some(dir);
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Rejects configurations the server cannot run with, however they were put together.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.seconds_per_day > 0,
+            "A day must last at least a second"
+        );
+        anyhow::ensure!(self.shards > 0, "At least one collector shard is needed");
+        anyhow::ensure!(
+            self.storage.is_none() || self.shutdown.backlog.is_none(),
+            "A ticket backlog is only needed without storage, which keeps the tickets in its snapshots"
+        );
+        anyhow::ensure!(
+            self.limits.plates_per_second != Some(0) && self.limits.plate_burst != Some(0),
+            "Cameras must be allowed to report plates"
+        );
+        let queues = &self.queues;
+        anyhow::ensure!(
+            queues.reporting > 0
+                && queues.subscriptions > 0
+                && queues.queries > 0
+                && queues.tickets > 0
+                && queues.sinks > 0,
+            "Queues need room for at least one entry"
+        );
+        anyhow::ensure!(
+            queues.unacked > 0,
+            "Dispatchers which acknowledge must be allowed an unacknowledged ticket"
+        );
+        Ok(())
+    }
+
+    pub fn journal_sync(&self) -> Duration {
+        Duration::from_millis(self.journal_sync)
+    }
+
+    pub fn log_level(&self) -> anyhow::Result<LevelFilter> {
+        self.log
+            .level
+            .parse()
+            .with_context(|| format!("Invalid log level {:?}", self.log.level))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use clap::Parser;
+
+    #[test]
+    fn arguments_override_file() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(
+            file.path(),
+            "seconds_per_day = 100\n[queues]\ntickets = 10\nreporting = 5\n",
+        )
+        .unwrap();
+        let path = file.path().to_str().unwrap();
+        let args = Arguments::parse_from(["speedd", "-c", path, "--ticket-capacity", "20"]);
+
+        let config = Config::from_arguments(args).unwrap();
+        assert_eq!(config.seconds_per_day, 100);
+        assert_eq!(
+            config.queues,
+            Queues {
+                reporting: 5,
+                tickets: 20,
+                ..Queues::default()
+            }
+        );
+        assert_eq!(config.address, Config::default().address);
+    }
+
+    #[test]
+    fn policy_per_road() {
+        let config: Config = toml::from_str(
+            "[policy]\nkind = \"exact\"\ntolerance = 50\n[[policy.roads]]\nroad = 66\nkind = \"rounded\"\n",
+        )
+        .unwrap();
+        assert_eq!(
+            config.policy,
+            Policy {
+                kind: PolicyKind::Exact,
+                tolerance: 50,
+                roads: vec![RoadPolicy {
+                    road: 66,
+                    kind: PolicyKind::Rounded,
+                    tolerance: 0,
+                }],
+            }
+        );
+    }
+}
@@ -1,41 +1,75 @@
-use crate::{
-    heartbeat,
-    server::{self, TicketRecord},
-    Road,
-};
+use crate::{heartbeat::Heartbeat, metrics::METRICS, shards::Shards};
 use async_channel as mpmc;
 use futures::{stream::SelectAll, Sink, SinkExt, Stream, StreamExt};
-use speedd_codecs::client;
-use tokio::sync::{mpsc, oneshot};
+use speedd_codecs::{
+    client,
+    server::{self, TicketRecord},
+};
+use std::collections::VecDeque;
+use tokio_util::sync::CancellationToken;
 
+/// Delivers tickets of its roads to a connected dispatcher client.
+///
+/// Tickets taken from the road queues are in flight until they are delivered. Without acknowledgements,
+/// that is when they have been written to the connection. A client which sent [`client::Message::WantAcks`]
+/// must confirm them with [`client::Message::Ack`], and gets no more tickets while a window of them is
+/// unacknowledged, leaving them to the other dispatchers of its roads. Tickets still in flight when the dispatcher is dropped,
+/// for example because the connection closed, are put back into their road queue.
+/// They are not part of the collector snapshots though, so a server crash still loses them.
 #[derive(Debug)]
 pub struct Dispatcher {
     tickets: SelectAll<mpmc::Receiver<TicketRecord>>,
+    shards: Shards,
+    acks: bool,
+    in_flight: VecDeque<TicketRecord>,
+    /// Unacknowledged tickets after which no more are taken
+    ack_window: usize,
+    strict: bool,
+    shutdown: CancellationToken,
 }
 
 impl Dispatcher {
-    pub async fn new(
-        roads: &[u16],
-        dispatchers: &mpsc::Sender<(Road, oneshot::Sender<mpmc::Receiver<TicketRecord>>)>,
-    ) -> anyhow::Result<Self> {
+    pub async fn new(roads: &[u16], shards: &Shards) -> anyhow::Result<Self> {
         let mut handles = Vec::new();
         for road in roads {
-            let (tx, rx) = oneshot::channel();
-            dispatchers.send((*road, tx)).await?;
-            let receiver = rx.await?;
-            handles.push(receiver);
+            handles.extend(shards.subscribe(*road).await?);
         }
         // TODO try with just iterator, no vector
-        let tickets = futures::stream::select_all(handles.into_iter());
-        Ok(Self { tickets })
+        let tickets = futures::stream::select_all(handles);
+        Ok(Self {
+            tickets,
+            shards: shards.clone(),
+            acks: false,
+            in_flight: VecDeque::new(),
+            ack_window: 16,
+            strict: true,
+            shutdown: CancellationToken::new(),
+        })
+    }
+
+    /// Whether to disconnect after sending an error, as the spec demands. On by default.
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Tickets an acknowledging client may have unacknowledged before it gets more. 16 by default.
+    pub fn with_ack_window(mut self, window: usize) -> Self {
+        self.ack_window = window;
+        self
+    }
+
+    /// Disconnects when `shutdown` is cancelled, even if there are tickets left.
+    pub fn with_shutdown(mut self, shutdown: CancellationToken) -> Self {
+        self.shutdown = shutdown;
+        self
     }
 
     pub async fn run<R, W>(
         mut self,
         mut reader: R,
         mut writer: W,
-        mut heartbeat_sender: Option<mpsc::Sender<()>>,
-        mut heartbeats: mpsc::Receiver<()>,
+        mut heartbeat: Heartbeat,
     ) -> anyhow::Result<()>
     where
         R: Stream<Item = Result<client::Message, anyhow::Error>> + Send + Unpin,
@@ -43,22 +77,43 @@ impl Dispatcher {
     {
         tracing::info!("Starting Dispatcher Client loop");
         loop {
+            let window_open = !self.acks || self.in_flight.len() < self.ack_window;
             tokio::select! {
+                // Shutdown goes first, then client messages, so a WantAcks sent along with
+                // IAmDispatcher is seen before the first ticket goes out
+                biased;
+                () = self.shutdown.cancelled() => {
+                    writer.send(server::Message::Error("Server is shutting down".to_string())).await?;
+                    break;
+                }
                 Some(msg) = reader.next() => {
                     match msg {
                         Ok(msg) => {
                             tracing::info!("Received dispatcher message {msg:?}");
-                            self.handle_client_message(msg, &mut writer, &mut heartbeat_sender).await?;
+                            if let Some(error) = self.handle_client_message(msg, &mut heartbeat) {
+                                writer.send(server::Message::Error(error)).await?;
+                                if self.strict {
+                                    break;
+                                }
+                            }
+                        }
+                        Err(e) => {
+                            METRICS.decode_errors.inc();
+                            writer.send(server::Message::Error(format!("Nahh... you're just a dispatcher. {e}"))).await?;
+                            if self.strict {
+                                break;
+                            }
                         }
-                        Err(e) => writer.send(server::Message::Error(format!("Nahh... you're just a dispatcher. {e:?}"))).await?,
                     }
                 }
-                Some(msg) = self.tickets.next() => {
+                Some(msg) = self.tickets.next(), if window_open => {
                     tracing::info!("Received ticket {msg:?}");
+                    self.in_flight.push_back(msg.clone());
                     writer.send(server::Message::Ticket(msg)).await?;
-                }
-                Some(()) = heartbeats.recv() => {
-                    writer.send(server::Message::Heartbeat).await?;
+                    if !self.acks {
+                        self.in_flight.pop_front();
+                        METRICS.tickets_delivered.inc();
+                    }
                 }
                 else => break
             }
@@ -67,55 +122,163 @@ impl Dispatcher {
         Ok(())
     }
 
-    async fn handle_client_message<W>(
-        &self,
+    /// Acts on a message from the dispatcher. Returns the error to send if the message was illegal.
+    fn handle_client_message(
+        &mut self,
         msg: client::Message,
-        writer: &mut W,
-        heartbeat_sender: &mut Option<mpsc::Sender<()>>,
-    ) -> anyhow::Result<()>
-    where
-        W: Sink<server::Message, Error = anyhow::Error> + Unpin,
-    {
-        match msg {
-            client::Message::Plate(_) => {
-                writer
-                    .send(server::Message::Error(
-                        "You Sir Dispatcher are confused".to_string(),
-                    ))
-                    .await?;
+        heartbeat: &mut Heartbeat,
+    ) -> Option<String> {
+        let error = match msg {
+            client::Message::Plate(_) => Some("You Sir Dispatcher are confused"),
+            client::Message::WantHeartbeat(dur) => heartbeat.request(dur).err(),
+            client::Message::IAmCamera { .. } => Some("No you're not (a camera)"),
+            client::Message::IAmDispatcher(_) => Some("Yes, you are (a dispatcher)"),
+            client::Message::WantAcks => {
+                tracing::info!("Dispatcher will acknowledge tickets");
+                self.acks = true;
+                None
             }
-            client::Message::WantHeartbeat(dur) => {
-                if let Some(heartbeat_sender) = heartbeat_sender.take() {
-                    if dur.is_zero() {
-                        tracing::warn!("Ignoring zero-duration heartbeat");
-                    } else {
-                        tracing::info!("Spawning a new heartbeat");
-                        tokio::spawn(heartbeat::run(dur, heartbeat_sender));
-                    }
+            client::Message::Ack(count) => {
+                if !self.acks {
+                    Some("You did not offer to acknowledge tickets")
+                } else if count as usize > self.in_flight.len() {
+                    // Which tickets it meant is anybody's guess, so they stay in flight
+                    Some("You acknowledged more tickets than you got")
                 } else {
-                    tracing::info!("Ignoring repeated heartbeat request");
-                    writer
-                        .send(server::Message::Error(
-                            "You already specified a heartbeat".to_string(),
-                        ))
-                        .await?;
+                    self.in_flight.drain(..count as usize);
+                    METRICS.tickets_delivered.inc_by(u64::from(count));
+                    None
                 }
             }
-            client::Message::IAmCamera { .. } => {
-                writer
-                    .send(server::Message::Error(
-                        "No you're not (a camera)".to_string(),
-                    ))
-                    .await?;
-            }
-            client::Message::IAmDispatcher(_) => {
-                writer
-                    .send(server::Message::Error(
-                        "Yes, you are (a dispatcher)".to_string(),
-                    ))
-                    .await?;
+        };
+        error.map(String::from)
+    }
+}
+
+impl Drop for Dispatcher {
+    fn drop(&mut self) {
+        for ticket in self.in_flight.drain(..) {
+            tracing::warn!("Requeueing ticket which was not delivered: {ticket:?}");
+            METRICS.tickets_requeued.inc();
+            self.shards.requeue(ticket);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{collector::Collector, config::Queues, heartbeat::Heartbeats};
+    use speedd_codecs::{camera::Camera, plate::PlateRecord};
+
+    fn ticket_writer() -> (
+        impl Sink<server::Message, Error = anyhow::Error> + Send + Unpin,
+        futures::channel::mpsc::UnboundedReceiver<server::Message>,
+    ) {
+        let (tx, rx) = futures::channel::mpsc::unbounded();
+        (tx.sink_map_err(anyhow::Error::from), rx)
+    }
+
+    #[tokio::test]
+    async fn requeues_unacknowledged_ticket_of_killed_dispatcher() {
+        let shards = Shards::spawn(vec![Collector::new()], &Queues::default());
+        for (timestamp, mile) in [(1, 2), (20, 4)] {
+            let record = PlateRecord {
+                plate: "ABC".to_string(),
+                timestamp,
+            };
+            let camera = Camera {
+                road: 12,
+                mile,
+                limit: 10,
+            };
+            shards.report(record, camera).await.unwrap();
+        }
+
+        // First dispatcher promises acks, gets the ticket, and dies before acknowledging it
+        let dispatcher = Dispatcher::new(&[12], &shards).await.unwrap();
+        let reader = futures::stream::iter([Ok(client::Message::WantAcks)])
+            .chain(futures::stream::pending());
+        let (writer, mut written) = ticket_writer();
+        let (writer, heartbeat) = Heartbeats::default().connection(writer);
+        let task = tokio::spawn(dispatcher.run(reader, writer, heartbeat));
+        let Some(server::Message::Ticket(ticket)) = written.next().await else {
+            panic!("Expected a ticket");
+        };
+        task.abort();
+        assert!(task.await.unwrap_err().is_cancelled());
+
+        // Second dispatcher gets the same ticket again
+        let dispatcher = Dispatcher::new(&[12], &shards).await.unwrap();
+        let (writer, mut written) = ticket_writer();
+        let (writer, heartbeat) = Heartbeats::default().connection(writer);
+        tokio::spawn(dispatcher.run(futures::stream::pending(), writer, heartbeat));
+        assert_eq!(written.next().await, Some(server::Message::Ticket(ticket)));
+    }
+
+    #[tokio::test]
+    async fn window_leaves_tickets_to_other_dispatchers() {
+        let shards = Shards::spawn(vec![Collector::new()], &Queues::default());
+        for plate in ["A", "B", "C", "D"] {
+            for (timestamp, mile) in [(1, 2), (20, 4)] {
+                let record = PlateRecord {
+                    plate: plate.to_string(),
+                    timestamp,
+                };
+                let camera = Camera {
+                    road: 12,
+                    mile,
+                    limit: 10,
+                };
+                shards.report(record, camera).await.unwrap();
             }
         }
-        Ok(())
+
+        // A dispatcher which never acknowledges holds on to one ticket only
+        let dispatcher = Dispatcher::new(&[12], &shards)
+            .await
+            .unwrap()
+            .with_ack_window(1);
+        let (messages, reader) = futures::channel::mpsc::unbounded();
+        messages
+            .unbounded_send(Ok(client::Message::WantAcks))
+            .unwrap();
+        let (writer, mut stalled) = ticket_writer();
+        let (writer, heartbeat) = Heartbeats::default().connection(writer);
+        tokio::spawn(dispatcher.run(reader, writer, heartbeat));
+        let Some(server::Message::Ticket(held)) = stalled.next().await else {
+            panic!("Expected a ticket");
+        };
+
+        let dispatcher = Dispatcher::new(&[12], &shards).await.unwrap();
+        let (writer, mut written) = ticket_writer();
+        let (writer, heartbeat) = Heartbeats::default().connection(writer);
+        tokio::spawn(dispatcher.run(futures::stream::pending(), writer, heartbeat));
+        let mut plates = Vec::new();
+        for _ in 0..3 {
+            let Some(server::Message::Ticket(ticket)) = written.next().await else {
+                panic!("Expected a ticket");
+            };
+            plates.push(ticket.plate);
+        }
+
+        // Acknowledging more than it got is an error, after which the held ticket moves on
+        messages
+            .unbounded_send(Ok(client::Message::Ack(2)))
+            .unwrap();
+        assert_eq!(
+            stalled.next().await,
+            Some(server::Message::Error(
+                "You acknowledged more tickets than you got".to_string()
+            ))
+        );
+        assert_eq!(stalled.next().await, None);
+        assert_eq!(
+            written.next().await,
+            Some(server::Message::Ticket(held.clone()))
+        );
+        plates.push(held.plate);
+        plates.sort();
+        assert_eq!(plates, ["A", "B", "C", "D"]);
     }
 }
@@ -0,0 +1,108 @@
+use crate::config;
+use serde::{Deserialize, Serialize};
+use speedd_codecs::{server::TicketRecord, Limit};
+
+/// Fines by how far a car went over the limit. The fine of the highest bracket reached applies,
+/// nothing below the lowest one.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Tariff {
+    /// Sorted by `over`
+    brackets: Vec<config::Bracket>,
+}
+
+impl Tariff {
+    pub fn new(mut brackets: Vec<config::Bracket>) -> Self {
+        brackets.sort_by_key(|bracket| bracket.over);
+        Self { brackets }
+    }
+
+    /// Fine for going `over` hundredths of mph over the limit.
+    pub fn fine(&self, over: u16) -> u32 {
+        self.brackets
+            .iter()
+            .take_while(|bracket| u32::from(bracket.over) * 100 <= u32::from(over))
+            .last()
+            .map_or(0, |bracket| bracket.fine)
+    }
+}
+
+/// A ticket in a plate's ledger.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LedgerEntry {
+    pub ticket: TicketRecord,
+    /// Speed limit in mph at the time
+    pub limit_mph: Limit,
+    /// Hundredths of mph over the limit
+    pub over_centi_mph: u16,
+    pub fine: u32,
+}
+
+impl LedgerEntry {
+    pub fn new(ticket: &TicketRecord, limit: Limit, tariff: &Tariff) -> Self {
+        let over = ticket.speed.saturating_sub(limit.saturating_mul(100));
+        Self {
+            ticket: ticket.clone(),
+            limit_mph: limit,
+            over_centi_mph: over,
+            fine: tariff.fine(over),
+        }
+    }
+}
+
+/// A plate's ticket history, as answered to queries.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Ledger {
+    pub plate: String,
+    /// In the order the tickets were issued
+    pub tickets: Vec<LedgerEntry>,
+    /// Sum of the fines, none of which are recorded as paid
+    pub outstanding: u64,
+}
+
+impl Ledger {
+    pub fn new(plate: String, tickets: Vec<LedgerEntry>) -> Self {
+        let outstanding = tickets.iter().map(|entry| u64::from(entry.fine)).sum();
+        Self {
+            plate,
+            tickets,
+            outstanding,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn fines_by_bracket() {
+        let tariff = Tariff::new(vec![
+            config::Bracket {
+                over: 20,
+                fine: 300,
+            },
+            config::Bracket { over: 0, fine: 50 },
+            config::Bracket {
+                over: 10,
+                fine: 120,
+            },
+        ]);
+        let fines = [0, 999, 1000, 1999, 2000, 65535].map(|over| tariff.fine(over));
+        assert_eq!(fines, [50, 50, 120, 120, 300, 300]);
+        assert_eq!(Tariff::default().fine(5000), 0);
+
+        let ticket = TicketRecord {
+            plate: "UN1X".to_string(),
+            road: 123,
+            mile1: 8,
+            timestamp1: 0,
+            mile2: 9,
+            timestamp2: 45,
+            speed: 8000,
+        };
+        let entry = LedgerEntry::new(&ticket, 60, &tariff);
+        assert_eq!((entry.over_centi_mph, entry.fine), (2000, 300));
+        let ledger = Ledger::new("UN1X".to_string(), vec![entry.clone(), entry]);
+        assert_eq!(ledger.outstanding, 600);
+    }
+}
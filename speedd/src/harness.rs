@@ -0,0 +1,357 @@
+//! End-to-end test harness: serves connections over in-memory streams, exactly like the accept loop
+//! does, and scripts clients as actors speaking bytes or [`ClientMessage`]s.
+//!
+//! Meant for tests with a paused clock (`#[tokio::test(start_paused = true)]`), so waits take no real
+//! time and every message arrives at a predictable instant.
+
+use crate::{
+    config::Config,
+    server::{Services, ShutdownSummary},
+};
+use futures::{SinkExt, StreamExt};
+use speedd_codecs::{
+    camera::Camera,
+    client::{encoder::MessageEncoder, Message as ClientMessage},
+    plate::PlateRecord,
+    server::{self, decoder::MessageDecoder},
+    Limit, Mile, Road, Timestamp,
+};
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf},
+    time::Instant,
+};
+use tokio_util::codec::{FramedRead, FramedWrite};
+
+/// How long [`Actor::recv`] waits for a message before giving up.
+const RECV_TIMEOUT: Duration = Duration::from_secs(3600);
+
+pub struct Harness {
+    services: Services,
+    start: Instant,
+    connections: u16,
+}
+
+impl Harness {
+    /// Spawns the collector shards. Time is measured from here.
+    pub fn new(config: Config) -> Self {
+        Self {
+            services: Services::new(config).expect("Failed to set up the server"),
+            start: Instant::now(),
+            connections: 0,
+        }
+    }
+
+    /// Opens a connection, which comes from a new port of 127.0.0.1.
+    pub fn connect(&mut self) -> Actor {
+        self.connections += 1;
+        let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, self.connections));
+        let (client, server) = tokio::io::duplex(4096);
+        let session = self
+            .services
+            .sessions
+            .register(addr)
+            .expect("Too many connections");
+        self.services.serve(server, session);
+        let (reader, writer) = tokio::io::split(client);
+        Actor {
+            reader: FramedRead::new(reader, MessageDecoder),
+            writer: FramedWrite::new(writer, MessageEncoder),
+            start: self.start,
+        }
+    }
+
+    /// Connects a camera and identifies it.
+    pub async fn camera(&mut self, road: Road, mile: Mile, limit: Limit) -> Actor {
+        let mut actor = self.connect();
+        actor
+            .send(ClientMessage::IAmCamera(Camera { road, mile, limit }))
+            .await;
+        actor
+    }
+
+    /// Shuts the server down like the accept loop does on its shutdown signal.
+    pub async fn shut_down(&self) -> ShutdownSummary {
+        self.services
+            .shut_down()
+            .await
+            .expect("Failed to shut down")
+    }
+
+    /// Connects a dispatcher and identifies it.
+    pub async fn dispatcher(&mut self, roads: &[Road]) -> Actor {
+        let mut actor = self.connect();
+        actor
+            .send(ClientMessage::IAmDispatcher(roads.to_vec()))
+            .await;
+        actor
+    }
+}
+
+/// A scripted client.
+pub struct Actor {
+    reader: FramedRead<ReadHalf<DuplexStream>, MessageDecoder>,
+    writer: FramedWrite<WriteHalf<DuplexStream>, MessageEncoder>,
+    start: Instant,
+}
+
+impl Actor {
+    pub async fn send(&mut self, msg: ClientMessage) {
+        self.writer.send(msg).await.expect("Failed to send");
+    }
+
+    /// Writes raw bytes, which need not be a valid message.
+    pub async fn send_bytes(&mut self, bytes: &[u8]) {
+        self.writer
+            .get_mut()
+            .write_all(bytes)
+            .await
+            .expect("Failed to send");
+    }
+
+    pub async fn plate(&mut self, plate: &str, timestamp: Timestamp) {
+        self.send(ClientMessage::Plate(PlateRecord {
+            plate: plate.to_string(),
+            timestamp,
+        }))
+        .await;
+    }
+
+    /// The next message from the server, or `None` once the server closed the connection.
+    ///
+    /// # Panics
+    /// Panics if nothing happens within an hour, which is instant with a paused clock.
+    pub async fn recv(&mut self) -> Option<server::Message> {
+        tokio::time::timeout(RECV_TIMEOUT, self.reader.next())
+            .await
+            .expect("The server sent nothing for an hour")
+            .map(|msg| msg.expect("The server sent an undecodable message"))
+    }
+
+    /// Expects exactly these messages next.
+    pub async fn expect(&mut self, expected: &[server::Message]) {
+        for msg in expected {
+            assert_eq!(self.recv().await.as_ref(), Some(msg));
+        }
+    }
+
+    /// Expects `expected` to be the next message, `at` after the harness was created.
+    pub async fn expect_at(&mut self, expected: server::Message, at: Duration) {
+        assert_eq!(self.recv().await.as_ref(), Some(&expected));
+        assert_eq!(
+            self.start.elapsed(),
+            at,
+            "{expected:?} arrived at another time"
+        );
+    }
+
+    /// Expects the server to close the connection, without sending anything before.
+    pub async fn expect_closed(&mut self) {
+        let next = self.recv().await;
+        assert!(
+            next.is_none(),
+            "Expected the connection to close, got {next:?}"
+        );
+    }
+
+    /// Expects nothing to arrive for `duration`.
+    pub async fn expect_silence(&mut self, duration: Duration) {
+        if let Ok(next) = tokio::time::timeout(duration, self.reader.next()).await {
+            panic!("Expected silence, got {next:?}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use speedd_codecs::server::TicketRecord;
+
+    fn config() -> Config {
+        Config {
+            shards: 2,
+            ..Config::default()
+        }
+    }
+
+    fn error(msg: &str) -> server::Message {
+        server::Message::Error(msg.to_string())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn example_session() {
+        let mut harness = Harness::new(config());
+        let mut camera1 = harness.camera(123, 8, 60).await;
+        camera1.plate("UN1X", 0).await;
+        let mut camera2 = harness.camera(123, 9, 60).await;
+        camera2.plate("UN1X", 45).await;
+        let mut dispatcher = harness.dispatcher(&[123]).await;
+        dispatcher
+            .expect(&[server::Message::Ticket(TicketRecord {
+                plate: "UN1X".to_string(),
+                road: 123,
+                mile1: 8,
+                timestamp1: 0,
+                mile2: 9,
+                timestamp2: 45,
+                speed: 8000,
+            })])
+            .await;
+        dispatcher.expect_silence(Duration::from_secs(60)).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn heartbeats_on_time() {
+        let mut harness = Harness::new(config());
+        let mut camera = harness.camera(1, 10, 60).await;
+        camera
+            .send(ClientMessage::WantHeartbeat(Duration::from_millis(2500)))
+            .await;
+        let mut dispatcher = harness.dispatcher(&[1]).await;
+        dispatcher
+            .send(ClientMessage::WantHeartbeat(Duration::from_secs(1)))
+            .await;
+        tokio::join!(
+            async {
+                for i in 0..3 {
+                    camera
+                        .expect_at(server::Message::Heartbeat, Duration::from_millis(2500) * i)
+                        .await;
+                }
+            },
+            async {
+                for i in 0..6 {
+                    dispatcher
+                        .expect_at(server::Message::Heartbeat, Duration::from_secs(i))
+                        .await;
+                }
+            }
+        );
+
+        // A ticket goes out right away, between the heartbeats
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        camera.plate("FAST", 0).await;
+        harness.camera(1, 20, 60).await.plate("FAST", 60).await;
+        let ticket = match dispatcher.recv().await {
+            Some(server::Message::Ticket(ticket)) => ticket,
+            other => panic!("Expected a ticket, got {other:?}"),
+        };
+        assert_eq!((ticket.plate.as_str(), ticket.speed), ("FAST", 60000));
+        assert_eq!(harness.start.elapsed(), Duration::from_millis(5300));
+        dispatcher
+            .expect_at(server::Message::Heartbeat, Duration::from_secs(6))
+            .await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn byte_level_errors() {
+        let mut harness = Harness::new(config());
+
+        // Plate "UN1X" at 1000 from a client which did not identify
+        let mut client = harness.connect();
+        client
+            .send_bytes(&[0x20, 0x04, 0x55, 0x4e, 0x31, 0x58, 0x00, 0x00, 0x03, 0xe8])
+            .await;
+        client.expect(&[error("You are no camera")]).await;
+        client.expect_closed().await;
+
+        // IAmDispatcher for road 66 and then a message type which does not exist
+        let mut client = harness.connect();
+        client.send_bytes(&[0x81, 0x01, 0x00, 0x42, 0xff]).await;
+        match client.recv().await {
+            Some(server::Message::Error(_)) => {}
+            other => panic!("Expected an error, got {other:?}"),
+        }
+        client.expect_closed().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn normalized_plates_share_a_history() {
+        let mut config = config();
+        config.plates.uppercase = true;
+        config.plates.strip = true;
+        config.plates.ocr_confusions = true;
+        let mut harness = Harness::new(config);
+        harness.camera(7, 0, 60).await.plate("re-05 bkg", 0).await;
+        harness.camera(7, 2, 60).await.plate("RE058KG", 60).await;
+        let mut dispatcher = harness.dispatcher(&[7]).await;
+        let Some(server::Message::Ticket(ticket)) = dispatcher.recv().await else {
+            panic!("Expected a ticket");
+        };
+        // The ticket names the plate as it was first read
+        assert_eq!((ticket.plate.as_str(), ticket.speed), ("re-05 bkg", 12000));
+
+        // A bad read is dropped, but the camera stays connected and keeps reporting
+        let mut camera = harness.camera(7, 4, 60).await;
+        camera.plate("--", 120).await;
+        camera.plate("RE-058KG", 120).await;
+        camera.expect_silence(Duration::from_secs(1)).await;
+        let health = harness.services.cameras.health();
+        let rejected = health
+            .iter()
+            .map(|camera| camera.rejected_plates)
+            .collect::<Vec<_>>();
+        assert_eq!(rejected, [0, 0, 1]);
+        let trajectory = harness
+            .services
+            .shards
+            .trajectory("RE058KG".to_string())
+            .await
+            .unwrap()
+            .unwrap();
+        let miles = trajectory
+            .hits
+            .iter()
+            .map(|hit| hit.mile)
+            .collect::<Vec<_>>();
+        assert_eq!(miles, [0, 2, 4]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shutdown_drains_and_saves_backlog() {
+        let dir = tempfile::tempdir().unwrap();
+        let backlog = dir.path().join("backlog.jsonl");
+        let mut config = config();
+        config.shutdown.backlog = Some(backlog.clone());
+        let mut harness = Harness::new(config.clone());
+
+        let mut dispatcher = harness.dispatcher(&[1]).await;
+        let mut cameras = Vec::new();
+        for (mile, timestamp) in [(0, 0), (10, 60)] {
+            for road in [1, 2] {
+                let mut camera = harness.camera(road, mile, 60).await;
+                camera.plate(&format!("CAR{road}"), timestamp).await;
+                cameras.push(camera);
+            }
+        }
+        let Some(server::Message::Ticket(delivered)) = dispatcher.recv().await else {
+            panic!("Expected a ticket");
+        };
+        assert_eq!(delivered.road, 1);
+
+        let summary = harness.shut_down().await;
+        assert_eq!(summary.undelivered, [(2, 1)].into());
+        assert_eq!(summary.to_string(), "1 undelivered tickets: 1 for road 2");
+        for mut camera in cameras {
+            camera.expect(&[error("Server is shutting down")]).await;
+            camera.expect_closed().await;
+        }
+        dispatcher.expect(&[error("Server is shutting down")]).await;
+        dispatcher.expect_closed().await;
+
+        // The next server picks the backlog up, and keeps it until it shut down in turn
+        let mut harness = Harness::new(config.clone());
+        let consumed = config.shutdown.consumed_backlog().unwrap();
+        assert!(!backlog.exists() && consumed.exists());
+        let mut dispatcher = harness.dispatcher(&[2]).await;
+        let Some(server::Message::Ticket(ticket)) = dispatcher.recv().await else {
+            panic!("Expected a ticket");
+        };
+        assert_eq!(ticket.plate, "CAR2");
+        assert_eq!(harness.shut_down().await, ShutdownSummary::default());
+        assert!(!backlog.exists() && !consumed.exists());
+    }
+}
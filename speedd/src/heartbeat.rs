@@ -1,19 +1,495 @@
-use std::time::Duration;
-use tokio::sync::mpsc;
+use crate::metrics::METRICS;
+use futures::{Sink, SinkExt};
+use speedd_codecs::server;
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+use tokio::{sync::Notify, time::Instant};
 
-/// Sends heartbeat signals at the specified interval on the provided `mpsc::Sender<()>`.
-/// When the `mpsc::Receiver<()>` handle is dropped or the channel is closed, it will drop.
+/// Resolution of the wheel. The protocol asks for heartbeats in deciseconds, so this is exact.
+const TICK: Duration = Duration::from_millis(100);
+/// Number of slots, covering intervals of up to 51.2s in a single turn of the wheel.
+/// Longer intervals wait for several turns in their slot.
+const SLOTS: usize = 512;
+
+/// Schedules the heartbeats of all connections on a single timer wheel, driven by one task
+/// which writes the heartbeats to the connections itself.
 ///
-/// # Panics
-/// Panics if the provided duration is zero.
-pub async fn run(dur: Duration, sender: mpsc::Sender<()>) {
-    let mut interval = tokio::time::interval(dur);
-    loop {
-        interval.tick().await;
+/// Every connection which asked for heartbeats only costs an entry in the wheel, instead of a task
+/// with its own timer and channel, and its task is not woken for heartbeats at all.
+/// Heartbeats are due on the ticks of the wheel, so they may be up to one tick late, but they do
+/// not drift. The task sleeps until the next tick with heartbeats due, is spawned on demand and
+/// ends when no connection wants heartbeats any longer.
+#[derive(Clone, Debug, Default)]
+pub struct Heartbeats {
+    wheel: Arc<Mutex<Wheel>>,
+    /// Wakes the task when a connection is added, which may be due before the task would wake
+    rescheduled: Arc<Notify>,
+}
+
+struct Wheel {
+    start: Instant,
+    /// The next tick to process
+    current: u64,
+    slots: Vec<Vec<u64>>,
+    entries: HashMap<u64, Entry>,
+    /// Entries whose last heartbeat is not written out yet, retried on the next tick
+    stalled: Vec<u64>,
+    next_id: u64,
+    running: bool,
+}
+
+impl std::fmt::Debug for Wheel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Wheel")
+            .field("current", &self.current)
+            .field("entries", &self.entries.len())
+            .field("stalled", &self.stalled.len())
+            .field("running", &self.running)
+            .finish_non_exhaustive()
+    }
+}
+
+struct Entry {
+    /// Interval in ticks
+    interval: u64,
+    /// Tick of the next heartbeat
+    due: u64,
+    writer: Arc<Mutex<dyn Beat>>,
+    stall: Option<Stall>,
+}
+
+/// How far a heartbeat got when the connection's writer could not take it right away.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Stall {
+    /// The writer is full, the heartbeat is still owed
+    Unsent,
+    /// The heartbeat is buffered, but not flushed yet
+    Unflushed,
+}
+
+impl Entry {
+    /// Writes the heartbeat which is due. Returns false if it is not out yet.
+    /// A connection which still owes one does not get another.
+    fn beat(&mut self) -> bool {
+        if self.stall != Some(Stall::Unsent) {
+            self.stall = self.writer.lock().unwrap().beat();
+        }
+        self.stall.is_none()
+    }
+
+    /// Continues writing a stalled heartbeat. Returns false if it is still not out.
+    fn resume(&mut self) -> bool {
+        self.stall = match self.stall {
+            Some(Stall::Unsent) => self.writer.lock().unwrap().beat(),
+            Some(Stall::Unflushed) => self.writer.lock().unwrap().flush(),
+            None => None,
+        };
+        self.stall.is_none()
+    }
+}
+
+impl Default for Wheel {
+    fn default() -> Self {
+        Self {
+            start: Instant::now(),
+            current: 0,
+            slots: vec![Vec::new(); SLOTS],
+            entries: HashMap::new(),
+            stalled: Vec::new(),
+            next_id: 0,
+            running: false,
+        }
+    }
+}
+
+impl Wheel {
+    fn ticks(&self, duration: Duration) -> u64 {
+        (duration.as_nanos() / TICK.as_nanos()) as u64
+    }
+
+    fn instant(&self, tick: u64) -> Instant {
+        self.start + Duration::from_millis(tick * TICK.as_millis() as u64)
+    }
+
+    fn schedule(&mut self, id: u64, due: u64) {
+        self.slots[(due % SLOTS as u64) as usize].push(id);
+    }
+
+    /// The next tick with anything to do. Slots of entries which are due in a later turn of the
+    /// wheel count as well, the task just schedules them again.
+    fn next_tick(&self) -> u64 {
+        if !self.stalled.is_empty() {
+            return self.current;
+        }
+        (self.current..self.current + SLOTS as u64)
+            .find(|tick| !self.slots[(tick % SLOTS as u64) as usize].is_empty())
+            .unwrap_or(self.current)
+    }
+
+    /// Writes the heartbeats of all ticks up to now, after retrying the stalled ones.
+    fn advance(&mut self) {
+        for id in std::mem::take(&mut self.stalled) {
+            if let Some(entry) = self.entries.get_mut(&id) {
+                if !entry.resume() {
+                    self.stalled.push(id);
+                }
+            }
+        }
+        let now = self.ticks(Instant::now().duration_since(self.start));
+        while self.current <= now {
+            let tick = self.current;
+            let slot = std::mem::take(&mut self.slots[(tick % SLOTS as u64) as usize]);
+            for id in slot {
+                // Entries of connections which went away are dropped here
+                let Some(entry) = self.entries.get_mut(&id) else {
+                    continue;
+                };
+                if entry.due == tick {
+                    let stalled = entry.stall.is_some();
+                    if !entry.beat() && !stalled {
+                        self.stalled.push(id);
+                    }
+                    entry.due += entry.interval;
+                }
+                let due = entry.due;
+                self.schedule(id, due);
+            }
+            self.current += 1;
+        }
+    }
+}
+
+impl Heartbeats {
+    /// Heartbeats for a new connection, which has not asked for any yet. The connection writes
+    /// through the returned writer, which it shares with the wheel.
+    pub fn connection<W>(&self, writer: W) -> (Writer<W>, Heartbeat)
+    where
+        W: Sink<server::Message> + Send + Unpin + 'static,
+    {
+        let shared = Arc::new(Mutex::new(Shared {
+            sink: writer,
+            waker: None,
+        }));
+        let heartbeat = Heartbeat {
+            heartbeats: self.clone(),
+            writer: shared.clone(),
+            state: State::Unrequested,
+        };
+        (Writer { shared }, heartbeat)
+    }
+
+    fn register(&self, interval: Duration, writer: Arc<Mutex<dyn Beat>>) -> u64 {
+        let mut wheel = self.wheel.lock().unwrap();
+        let id = wheel.next_id;
+        wheel.next_id += 1;
+        let interval = wheel
+            .ticks(interval + TICK - Duration::from_nanos(1))
+            .max(1);
+        let elapsed = Instant::now().duration_since(wheel.start);
+        if !wheel.running {
+            // Skip the ticks nobody was waiting for
+            wheel.current = wheel.current.max(wheel.ticks(elapsed));
+        }
+        let due =
+            (wheel.ticks(elapsed + TICK - Duration::from_nanos(1)) + interval).max(wheel.current);
+        let mut entry = Entry {
+            interval,
+            due,
+            writer,
+            stall: None,
+        };
+        // The first heartbeat goes out right away
+        if !entry.beat() {
+            wheel.stalled.push(id);
+        }
+        wheel.entries.insert(id, entry);
+        wheel.schedule(id, due);
+        if wheel.running {
+            self.rescheduled.notify_one();
+        } else {
+            wheel.running = true;
+            // Writing to many connections at once would exhaust tokio's cooperative budget,
+            // after which the connections only report that they are not ready
+            tokio::spawn(tokio::task::unconstrained(self.clone().run()));
+        }
+        id
+    }
+
+    fn deregister(&self, id: u64) {
+        self.wheel.lock().unwrap().entries.remove(&id);
+    }
+
+    async fn run(self) {
+        tracing::debug!("Starting heartbeat scheduler");
+        loop {
+            let next = {
+                let mut wheel = self.wheel.lock().unwrap();
+                if wheel.entries.is_empty() {
+                    wheel.running = false;
+                    // Only ids of connections which went away are left
+                    wheel.slots.iter_mut().for_each(Vec::clear);
+                    wheel.stalled.clear();
+                    break;
+                }
+                wheel.instant(wheel.next_tick())
+            };
+            tokio::select! {
+                () = tokio::time::sleep_until(next) => self.wheel.lock().unwrap().advance(),
+                () = self.rescheduled.notified() => {}
+            }
+        }
+        tracing::debug!("Stopping heartbeat scheduler");
+    }
+}
+
+/// A connection's writer as the wheel sees it.
+trait Beat: Send {
+    /// Writes a heartbeat without waiting. Returns how far it got if it is not out yet.
+    fn beat(&mut self) -> Option<Stall>;
+
+    /// Flushes a buffered heartbeat without waiting.
+    fn flush(&mut self) -> Option<Stall>;
+}
+
+/// The sink of a connection, along with the waker of the connection's task.
+struct Shared<W> {
+    sink: W,
+    /// The wheel polls the sink with this waker. Polling with another one would take the place of
+    /// the connection's own in the stream, which would then never wake the connection again.
+    waker: Option<Waker>,
+}
+
+impl<W> Beat for Shared<W>
+where
+    W: Sink<server::Message> + Send + Unpin,
+{
+    fn beat(&mut self) -> Option<Stall> {
+        let mut cx = Context::from_waker(self.waker.as_ref().unwrap_or(Waker::noop()));
+        match self.sink.poll_ready_unpin(&mut cx) {
+            Poll::Pending => return Some(Stall::Unsent),
+            // The connection sees write errors itself
+            Poll::Ready(Err(_)) => return None,
+            Poll::Ready(Ok(())) => {}
+        }
         tracing::trace!("Sending heartbeat");
-        if sender.send(()).await.is_err() {
-            tracing::info!("Dropping heartbeat");
-            return;
+        if self
+            .sink
+            .start_send_unpin(server::Message::Heartbeat)
+            .is_err()
+        {
+            return None;
+        }
+        self.flush()
+    }
+
+    fn flush(&mut self) -> Option<Stall> {
+        let mut cx = Context::from_waker(self.waker.as_ref().unwrap_or(Waker::noop()));
+        match self.sink.poll_flush_unpin(&mut cx) {
+            Poll::Pending => Some(Stall::Unflushed),
+            Poll::Ready(_) => None,
+        }
+    }
+}
+
+/// The writing half of a connection, shared with the heartbeat wheel.
+pub struct Writer<W> {
+    shared: Arc<Mutex<Shared<W>>>,
+}
+
+impl<W> Writer<W> {
+    fn poll<T>(&self, cx: &mut Context<'_>, f: impl FnOnce(Pin<&mut W>, &mut Context<'_>) -> T) -> T
+    where
+        W: Unpin,
+    {
+        let mut shared = self.shared.lock().unwrap();
+        if !shared
+            .waker
+            .as_ref()
+            .is_some_and(|waker| waker.will_wake(cx.waker()))
+        {
+            shared.waker = Some(cx.waker().clone());
+        }
+        f(Pin::new(&mut shared.sink), cx)
+    }
+}
+
+impl<W> Sink<server::Message> for Writer<W>
+where
+    W: Sink<server::Message> + Unpin,
+{
+    type Error = W::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.poll(cx, W::poll_ready)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: server::Message) -> Result<(), Self::Error> {
+        self.shared.lock().unwrap().sink.start_send_unpin(item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.poll(cx, W::poll_flush)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.poll(cx, W::poll_close)
+    }
+}
+
+#[derive(Debug)]
+enum State {
+    Unrequested,
+    /// Asked for heartbeats with an interval of zero
+    Off,
+    Running {
+        id: u64,
+    },
+}
+
+/// The heartbeats of one connection. Leaves the wheel when dropped.
+pub struct Heartbeat {
+    heartbeats: Heartbeats,
+    writer: Arc<Mutex<dyn Beat>>,
+    state: State,
+}
+
+impl Heartbeat {
+    /// Starts heartbeats at the given interval, or none at all for a zero interval.
+    /// Returns the error to send if the connection asked before.
+    pub fn request(&mut self, interval: Duration) -> Result<(), &'static str> {
+        if !matches!(self.state, State::Unrequested) {
+            tracing::info!("Ignoring repeated heartbeat request");
+            return Err("You already specified a heartbeat");
+        }
+        if interval.is_zero() {
+            tracing::warn!("Ignoring zero-duration heartbeat");
+            self.state = State::Off;
+        } else {
+            tracing::info!("Starting heartbeats every {interval:?}");
+            let id = self.heartbeats.register(interval, self.writer.clone());
+            METRICS.heartbeats_active.inc();
+            self.state = State::Running { id };
+        }
+        Ok(())
+    }
+
+    /// Whether heartbeats are sent.
+    pub fn is_running(&self) -> bool {
+        matches!(self.state, State::Running { .. })
+    }
+}
+
+impl Drop for Heartbeat {
+    fn drop(&mut self) {
+        if let State::Running { id } = self.state {
+            self.heartbeats.deregister(id);
+            METRICS.heartbeats_active.dec();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::{channel::mpsc, StreamExt};
+
+    type Received = mpsc::UnboundedReceiver<server::Message>;
+
+    fn connection(heartbeats: &Heartbeats) -> (Heartbeat, Received) {
+        let (sender, receiver) = mpsc::unbounded();
+        let (_writer, heartbeat) = heartbeats.connection(sender);
+        (heartbeat, receiver)
+    }
+
+    async fn beat(received: &mut Received) {
+        assert_eq!(received.next().await, Some(server::Message::Heartbeat));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ticks_on_the_wheel() {
+        let heartbeats = Heartbeats::default();
+        let start = Instant::now();
+        let (mut fast, mut fast_beats) = connection(&heartbeats);
+        fast.request(Duration::from_millis(300)).unwrap();
+        assert_eq!(
+            fast.request(Duration::from_secs(1)),
+            Err("You already specified a heartbeat")
+        );
+        let (mut slow, mut slow_beats) = connection(&heartbeats);
+        // Longer than a turn of the wheel
+        slow.request(Duration::from_secs(60)).unwrap();
+        let (mut off, _) = connection(&heartbeats);
+        off.request(Duration::ZERO).unwrap();
+        assert!(!off.is_running());
+
+        for expected in [0, 300, 600, 900] {
+            beat(&mut fast_beats).await;
+            assert_eq!(start.elapsed(), Duration::from_millis(expected));
+        }
+        beat(&mut slow_beats).await;
+        assert_eq!(start.elapsed(), Duration::from_millis(900));
+        beat(&mut slow_beats).await;
+        assert_eq!(start.elapsed(), Duration::from_secs(60));
+
+        // Off the tick grid, the next heartbeat is rounded up to the next tick
+        drop(fast);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let (mut late, mut late_beats) = connection(&heartbeats);
+        late.request(Duration::from_millis(200)).unwrap();
+        beat(&mut late_beats).await;
+        beat(&mut late_beats).await;
+        assert_eq!(start.elapsed(), Duration::from_millis(60_300));
+        assert_eq!(heartbeats.wheel.lock().unwrap().entries.len(), 2);
+
+        // The scheduler stops without connections and starts again with the next one
+        drop((slow, late));
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(!heartbeats.wheel.lock().unwrap().running);
+        let (mut again, mut again_beats) = connection(&heartbeats);
+        again.request(Duration::from_millis(100)).unwrap();
+        beat(&mut again_beats).await;
+        beat(&mut again_beats).await;
+        assert_eq!(start.elapsed(), Duration::from_millis(61_400));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sleeps_until_the_next_heartbeat() {
+        let heartbeats = Heartbeats::default();
+        let (mut slow, mut slow_beats) = connection(&heartbeats);
+        slow.request(Duration::from_secs(5)).unwrap();
+        beat(&mut slow_beats).await;
+        tokio::time::sleep(Duration::from_millis(1050)).await;
+        // Nothing was due, so the ticks in between were never processed
+        assert_eq!(heartbeats.wheel.lock().unwrap().current, 0);
+        // A faster connection wakes the sleeping scheduler
+        let (mut fast, mut fast_beats) = connection(&heartbeats);
+        fast.request(Duration::from_millis(200)).unwrap();
+        beat(&mut fast_beats).await;
+        let start = Instant::now();
+        beat(&mut fast_beats).await;
+        assert_eq!(start.elapsed(), Duration::from_millis(250));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn owes_heartbeats_to_a_full_writer() {
+        let heartbeats = Heartbeats::default();
+        // Room for a single message
+        let (sender, mut received) = mpsc::channel(0);
+        let (_writer, mut heartbeat) = heartbeats.connection(sender);
+        heartbeat.request(Duration::from_millis(100)).unwrap();
+        tokio::time::sleep(Duration::from_millis(1050)).await;
+        let start = Instant::now();
+        // The heartbeats due while the writer was full are owed only once
+        assert_eq!(received.try_recv().unwrap(), server::Message::Heartbeat);
+        assert!(received.try_recv().is_err());
+        for expected in [50, 150] {
+            assert_eq!(received.next().await, Some(server::Message::Heartbeat));
+            assert_eq!(start.elapsed(), Duration::from_millis(expected));
         }
     }
 }
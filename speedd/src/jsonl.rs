@@ -0,0 +1,167 @@
+//! Files of one JSON value per line: the journal, the audit log, the capture and the ticket files.
+
+use anyhow::Context;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::Path,
+    sync::{mpsc, Arc},
+    thread::JoinHandle,
+};
+
+/// Lines a [`JsonlAppender`] may be behind its writer thread before appending waits for the disk.
+const APPENDER_CAPACITY: usize = 1024;
+
+/// Opens a file for appending, creating it if necessary. `what` names it in errors.
+pub fn open_append(path: impl AsRef<Path>, what: &str) -> anyhow::Result<File> {
+    let path = path.as_ref();
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open {what} {path:?}"))
+}
+
+/// Writes values one per line. Every line is written with a single write, so lines appended to
+/// the same file by other writers do not interleave.
+#[derive(Debug)]
+pub struct JsonlWriter<W> {
+    writer: W,
+}
+
+impl<W: Write> JsonlWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+
+    pub fn write(&mut self, value: &impl Serialize) -> anyhow::Result<()> {
+        self.writer.write_all(&line(value)?)?;
+        Ok(())
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+fn line(value: &impl Serialize) -> serde_json::Result<Vec<u8>> {
+    let mut line = serde_json::to_vec(value)?;
+    line.push(b'\n');
+    Ok(line)
+}
+
+/// A [`JsonlWriter`] on a thread of its own, so appending from the async runtime does not wait for
+/// the disk. Clones share the thread, which writes the lines in the order they were appended and
+/// flushes whenever it caught up. Dropping the last clone waits until everything is written.
+#[derive(Clone, Debug)]
+pub struct JsonlAppender {
+    thread: Arc<AppenderThread>,
+}
+
+#[derive(Debug)]
+struct AppenderThread {
+    lines: Option<mpsc::SyncSender<Vec<u8>>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl JsonlAppender {
+    /// Starts writing to `writer`. `what` names it in logs.
+    pub fn spawn(what: &str, writer: impl Write + Send + 'static) -> anyhow::Result<Self> {
+        let (lines, rx) = mpsc::sync_channel::<Vec<u8>>(APPENDER_CAPACITY);
+        let what = what.to_string();
+        let handle = std::thread::Builder::new()
+            .name(format!("speedd {what}"))
+            .spawn(move || {
+                let mut writer = BufWriter::new(writer);
+                while let Ok(line) = rx.recv() {
+                    let result = std::iter::once(line)
+                        .chain(rx.try_iter())
+                        .try_for_each(|line| writer.write_all(&line))
+                        .and_then(|()| writer.flush());
+                    if let Err(e) = result {
+                        tracing::error!("Failed to write to {what}: {e}");
+                    }
+                }
+            })
+            .context("Failed to start writer thread")?;
+        Ok(Self {
+            thread: Arc::new(AppenderThread {
+                lines: Some(lines),
+                handle: Some(handle),
+            }),
+        })
+    }
+
+    /// Queues a value for writing. Only waits while the writer thread is far behind.
+    pub fn append(&self, value: &impl Serialize) -> anyhow::Result<()> {
+        let lines = self.thread.lines.as_ref().expect("Set until dropped");
+        lines.send(line(value)?).context("Writer thread is gone")
+    }
+}
+
+impl Drop for AppenderThread {
+    fn drop(&mut self) {
+        // Closing the channel ends the thread once it wrote everything
+        self.lines.take();
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                tracing::error!("Writer thread panicked");
+            }
+        }
+    }
+}
+
+/// Reads values written one per line. A crash in the middle of appending leaves a torn last line
+/// behind, which is ignored. `what` names the file in errors.
+pub fn read<T: DeserializeOwned>(path: impl AsRef<Path>, what: &str) -> anyhow::Result<Vec<T>> {
+    let path = path.as_ref();
+    let file = File::open(path).with_context(|| format!("Failed to open {what} {path:?}"))?;
+    let mut values = Vec::new();
+    let mut lines = BufReader::new(file).lines().peekable();
+    while let Some(line) = lines.next() {
+        let line = line.with_context(|| format!("Failed to read {what} {path:?}"))?;
+        match serde_json::from_str(&line) {
+            Ok(value) => values.push(value),
+            Err(e) if lines.peek().is_none() => {
+                tracing::warn!("Ignoring incomplete last line {line:?} of {what} {path:?}: {e}");
+            }
+            Err(e) => return Err(e).with_context(|| format!("Corrupt {what} {path:?}")),
+        }
+    }
+    Ok(values)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn appends_in_order_and_skips_torn_line() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let appender =
+            JsonlAppender::spawn("test log", open_append(file.path(), "test log").unwrap())
+                .unwrap();
+        let clone = appender.clone();
+        for n in 0..100 {
+            [&appender, &clone][n % 2].append(&n).unwrap();
+        }
+        drop((appender, clone));
+        assert_eq!(
+            read::<usize>(file.path(), "test log").unwrap(),
+            (0..100).collect::<Vec<_>>()
+        );
+
+        let mut appended = open_append(file.path(), "test log").unwrap();
+        appended.write_all(b"[1, 2\n").unwrap();
+        let mut writer = JsonlWriter::new(appended);
+        assert_eq!(read::<usize>(file.path(), "test log").unwrap().len(), 100);
+        writer.write(&100).unwrap();
+        let error = read::<usize>(file.path(), "test log").unwrap_err();
+        assert!(error.to_string().starts_with("Corrupt test log"));
+    }
+}
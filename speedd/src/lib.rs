@@ -0,0 +1,67 @@
+//! Server for the speed camera protocol of Protohackers problem 6.
+//! The `speedd` binary is a thin wrapper around [`Server`], which can also be embedded in tests and tools.
+
+use audit::AuditLog;
+use collector::Collector;
+use config::Config;
+use std::path::Path;
+
+mod admin;
+pub mod arguments;
+mod audit;
+mod camera;
+mod cameras;
+mod capture;
+mod client;
+mod collector;
+pub mod config;
+mod dispatcher;
+mod fines;
+#[cfg(test)]
+mod harness;
+mod heartbeat;
+mod jsonl;
+mod metrics;
+mod persistence;
+mod plates;
+mod policy;
+mod ratelimit;
+mod server;
+mod sessions;
+mod shards;
+mod sinks;
+mod spill;
+mod tls;
+mod trajectory;
+
+pub use cameras::ConflictPolicy;
+pub use capture::{read_capture, CapturedMessage};
+pub use server::{Server, ServerBuilder, ServerHandle, ShutdownSummary};
+pub use sessions::Role;
+
+/// Feeds a log of observations through collectors partitioned like the server's shards and prints
+/// the audit log of the violations. The retention window moves per shard, so the tickets are the
+/// server's as long as `config.shards` is the same and the observations of each shard are in the
+/// order it received them.
+pub fn compute_tickets(config: &Config, observations: &Path) -> anyhow::Result<()> {
+    let plates = plates::PlateRules::new(config.plates.clone());
+    let audit = AuditLog::new(std::io::stdout())?;
+    let mut collectors = (0..config.shards)
+        .map(|_| {
+            let collector = Collector::new()
+                .with_seconds_per_day(config.seconds_per_day)
+                .with_policy(policy::from_config(&config.policy))
+                .with_plate_rules(plates.clone())
+                .with_audit_log(audit.clone());
+            match config.retention_days {
+                Some(days) => collector.with_retention_days(days),
+                None => collector,
+            }
+        })
+        .collect::<Vec<_>>();
+    for (record, camera) in persistence::read_observations(observations)? {
+        let shard = shards::shard(&plates, &record.plate, collectors.len());
+        collectors[shard].observe(record, camera)?;
+    }
+    Ok(())
+}
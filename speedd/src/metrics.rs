@@ -0,0 +1,243 @@
+use crate::collector::RoadStatus;
+use prometheus::{
+    Encoder, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use std::sync::LazyLock;
+
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+/// Counters and gauges exported on the admin interface under `/metrics`.
+#[derive(Debug)]
+pub struct Metrics {
+    registry: Registry,
+    pub plates_received: IntCounter,
+    pub tickets_generated: IntCounter,
+    pub tickets_suppressed: IntCounter,
+    pub tickets_delivered: IntCounter,
+    pub tickets_requeued: IntCounter,
+    pub decode_errors: IntCounter,
+    pub connections_refused: IntCounter,
+    pub plates_throttled: IntCounter,
+    pub plates_rejected: IntCounter,
+    pub camera_conflicts: IntCounterVec,
+    pub tickets_forwarded: IntCounterVec,
+    pub sink_failures: IntCounterVec,
+    pub sink_drops: IntCounterVec,
+    pub heartbeats_active: IntGauge,
+    pub cameras_silent: IntGauge,
+    pub cameras_skewed: IntGauge,
+    pub retained_observations: IntGauge,
+    pub retained_ticketed_days: IntGauge,
+    pub connections: IntGaugeVec,
+    pub queued_tickets: IntGaugeVec,
+    pub spilled_tickets: IntGaugeVec,
+    pub road_dispatchers: IntGaugeVec,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry =
+            Registry::new_custom(Some("speedd".to_string()), None).expect("valid metrics prefix");
+        let metrics = Self {
+            plates_received: IntCounter::new("plates_received_total", "Plate reports received")
+                .unwrap(),
+            tickets_generated: IntCounter::new(
+                "tickets_generated_total",
+                "Tickets queued for dispatching",
+            )
+            .unwrap(),
+            tickets_suppressed: IntCounter::new(
+                "tickets_suppressed_total",
+                "Violations not ticketed because the plate was already ticketed that day",
+            )
+            .unwrap(),
+            tickets_delivered: IntCounter::new(
+                "tickets_delivered_total",
+                "Tickets written to a dispatcher",
+            )
+            .unwrap(),
+            tickets_requeued: IntCounter::new(
+                "tickets_requeued_total",
+                "Tickets put back into their road queue because a dispatcher went away",
+            )
+            .unwrap(),
+            decode_errors: IntCounter::new(
+                "decode_errors_total",
+                "Client messages which failed to decode",
+            )
+            .unwrap(),
+            connections_refused: IntCounter::new(
+                "connections_refused_total",
+                "Connections refused because their address had too many already",
+            )
+            .unwrap(),
+            plates_throttled: IntCounter::new(
+                "plates_throttled_total",
+                "Times a camera had to wait before it could report another plate",
+            )
+            .unwrap(),
+            plates_rejected: IntCounter::new(
+                "plates_rejected_total",
+                "Plate reports rejected by the plate rules",
+            )
+            .unwrap(),
+            camera_conflicts: IntCounterVec::new(
+                Opts::new(
+                    "camera_conflicts_total",
+                    "Cameras disagreeing with earlier ones, by kind of conflict",
+                ),
+                &["kind"],
+            )
+            .unwrap(),
+            tickets_forwarded: IntCounterVec::new(
+                Opts::new(
+                    "tickets_forwarded_total",
+                    "Tickets delivered to a ticket sink, by sink",
+                ),
+                &["sink"],
+            )
+            .unwrap(),
+            sink_failures: IntCounterVec::new(
+                Opts::new(
+                    "sink_failures_total",
+                    "Tickets a sink failed to take even after retrying, by sink",
+                ),
+                &["sink"],
+            )
+            .unwrap(),
+            sink_drops: IntCounterVec::new(
+                Opts::new(
+                    "sink_drops_total",
+                    "Tickets dropped for a sink which fell too far behind, by sink",
+                ),
+                &["sink"],
+            )
+            .unwrap(),
+            heartbeats_active: IntGauge::new(
+                "heartbeats_active",
+                "Connections receiving heartbeats",
+            )
+            .unwrap(),
+            cameras_silent: IntGauge::new(
+                "cameras_silent",
+                "Connected cameras which did not report a plate for too long",
+            )
+            .unwrap(),
+            cameras_skewed: IntGauge::new(
+                "cameras_skewed",
+                "Connected cameras whose clock is too far off the others on their road",
+            )
+            .unwrap(),
+            retained_observations: IntGauge::new(
+                "retained_observations",
+                "Plate observations held in memory",
+            )
+            .unwrap(),
+            retained_ticketed_days: IntGauge::new(
+                "retained_ticketed_days",
+                "Ticketed days per plate held in memory",
+            )
+            .unwrap(),
+            connections: IntGaugeVec::new(
+                Opts::new("connections", "Connected clients by role"),
+                &["role"],
+            )
+            .unwrap(),
+            queued_tickets: IntGaugeVec::new(
+                Opts::new(
+                    "queued_tickets",
+                    "Tickets waiting for a dispatcher, by road",
+                ),
+                &["road"],
+            )
+            .unwrap(),
+            spilled_tickets: IntGaugeVec::new(
+                Opts::new(
+                    "spilled_tickets",
+                    "Tickets waiting for a dispatcher on disk, by road",
+                ),
+                &["road"],
+            )
+            .unwrap(),
+            road_dispatchers: IntGaugeVec::new(
+                Opts::new("road_dispatchers", "Connected dispatchers, by road"),
+                &["road"],
+            )
+            .unwrap(),
+            registry,
+        };
+        metrics
+            .register()
+            .expect("metrics are registered exactly once");
+        metrics
+    }
+
+    fn register(&self) -> prometheus::Result<()> {
+        self.registry
+            .register(Box::new(self.plates_received.clone()))?;
+        self.registry
+            .register(Box::new(self.tickets_generated.clone()))?;
+        self.registry
+            .register(Box::new(self.tickets_suppressed.clone()))?;
+        self.registry
+            .register(Box::new(self.tickets_delivered.clone()))?;
+        self.registry
+            .register(Box::new(self.tickets_requeued.clone()))?;
+        self.registry
+            .register(Box::new(self.decode_errors.clone()))?;
+        self.registry
+            .register(Box::new(self.connections_refused.clone()))?;
+        self.registry
+            .register(Box::new(self.plates_throttled.clone()))?;
+        self.registry
+            .register(Box::new(self.plates_rejected.clone()))?;
+        self.registry
+            .register(Box::new(self.camera_conflicts.clone()))?;
+        self.registry
+            .register(Box::new(self.tickets_forwarded.clone()))?;
+        self.registry
+            .register(Box::new(self.sink_failures.clone()))?;
+        self.registry.register(Box::new(self.sink_drops.clone()))?;
+        self.registry
+            .register(Box::new(self.heartbeats_active.clone()))?;
+        self.registry
+            .register(Box::new(self.cameras_silent.clone()))?;
+        self.registry
+            .register(Box::new(self.cameras_skewed.clone()))?;
+        self.registry
+            .register(Box::new(self.retained_observations.clone()))?;
+        self.registry
+            .register(Box::new(self.retained_ticketed_days.clone()))?;
+        self.registry.register(Box::new(self.connections.clone()))?;
+        self.registry
+            .register(Box::new(self.queued_tickets.clone()))?;
+        self.registry
+            .register(Box::new(self.spilled_tickets.clone()))?;
+        self.registry
+            .register(Box::new(self.road_dispatchers.clone()))?;
+        Ok(())
+    }
+
+    /// Updates the per-road gauges from a fresh collector status.
+    pub fn set_roads(&self, roads: &[RoadStatus]) {
+        for road in roads {
+            let label = road.road.to_string();
+            self.queued_tickets
+                .with_label_values(&[&label])
+                .set(road.queued_tickets as i64);
+            self.spilled_tickets
+                .with_label_values(&[&label])
+                .set(road.spilled_tickets as i64);
+            self.road_dispatchers
+                .with_label_values(&[&label])
+                .set(road.dispatchers as i64);
+        }
+    }
+
+    /// Renders all metrics in the Prometheus text format.
+    pub fn render(&self) -> anyhow::Result<String> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8(buffer)?)
+    }
+}
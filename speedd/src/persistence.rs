@@ -0,0 +1,229 @@
+use crate::{
+    collector::Sighting,
+    fines::LedgerEntry,
+    jsonl::{self, JsonlWriter},
+    spill::SpillQueue,
+};
+use anyhow::Context;
+use serde::{ser::SerializeSeq, Deserialize, Serialize, Serializer};
+use speedd_codecs::{
+    camera::Camera, plate::PlateRecord, server::TicketRecord, Limit, Road, Timestamp,
+};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::{self, File},
+    io::{BufReader, BufWriter},
+    path::{Path, PathBuf},
+};
+
+const SNAPSHOT_FILE: &str = "snapshot.json";
+const JOURNAL_FILE: &str = "journal.jsonl";
+
+/// A plate sighting together with the camera that reported it.
+pub type Observation = (PlateRecord, Camera);
+
+/// Storage directories for each collector shard, `shard-0` to `shard-{n-1}` below `dir`.
+/// Plates are assigned to shards by hash, so state persisted by a different number of shards cannot be reused.
+pub fn shard_dirs(dir: impl AsRef<Path>, shards: usize) -> anyhow::Result<Vec<PathBuf>> {
+    let dir = dir.as_ref();
+    if dir.exists() {
+        let existing = fs::read_dir(dir)?
+            .filter_map(Result::ok)
+            .filter(|entry| entry.file_name().to_string_lossy().starts_with("shard-"))
+            .count();
+        anyhow::ensure!(
+            existing == 0 || existing == shards,
+            "{dir:?} holds state of {existing} shards, but {shards} are configured"
+        );
+    }
+    Ok((0..shards)
+        .map(|shard| dir.join(format!("shard-{shard}")))
+        .collect())
+}
+
+/// Reads a JSONL log of observations, such as a journal.
+pub fn read_observations(path: impl AsRef<Path>) -> anyhow::Result<Vec<Observation>> {
+    jsonl::read(path, "observation log")
+}
+
+/// Writes tickets as JSONL, replacing the file.
+pub fn write_tickets(path: impl AsRef<Path>, tickets: &[TicketRecord]) -> anyhow::Result<()> {
+    let path = path.as_ref();
+    let mut file = JsonlWriter::new(BufWriter::new(
+        File::create(path).with_context(|| format!("Failed to create ticket file {path:?}"))?,
+    ));
+    for ticket in tickets {
+        file.write(ticket)?;
+    }
+    file.into_inner().into_inner()?.sync_all()?;
+    Ok(())
+}
+
+/// Reads tickets written by [`write_tickets`].
+pub fn read_tickets(path: impl AsRef<Path>) -> anyhow::Result<Vec<TicketRecord>> {
+    jsonl::read(path, "ticket file")
+}
+
+/// Collector state as written to and read from a snapshot file.
+/// `pending` holds the tickets which were queued for a road, but not yet picked up by a dispatcher.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub records: HashMap<String, HashMap<Road, BTreeMap<Timestamp, Sighting>>>,
+    pub ticketed_days: HashMap<String, HashSet<u32>>,
+    pub limits: HashMap<Road, Limit>,
+    pub pending: HashMap<Road, Vec<TicketRecord>>,
+    pub ledger: HashMap<String, Vec<LedgerEntry>>,
+    pub spellings: HashMap<String, String>,
+    /// Day the retention window is counted back from
+    pub newest_day: u32,
+}
+
+/// Borrowed counterpart of [`Snapshot`], so taking a snapshot does not need to clone the collector state.
+#[derive(Debug, Serialize)]
+pub struct SnapshotRef<'a> {
+    pub records: &'a HashMap<String, HashMap<Road, BTreeMap<Timestamp, Sighting>>>,
+    pub ticketed_days: &'a HashMap<String, HashSet<u32>>,
+    pub limits: &'a HashMap<Road, Limit>,
+    pub pending: HashMap<Road, PendingTickets<'a>>,
+    pub ledger: &'a HashMap<String, Vec<LedgerEntry>>,
+    pub spellings: &'a HashMap<String, String>,
+    pub newest_day: u32,
+}
+
+/// The pending tickets of a road as a snapshot writes them: those taken out of its channel, then
+/// its backlog, which is read back from disk while it is written.
+#[derive(Debug)]
+pub struct PendingTickets<'a> {
+    pub queued: Vec<TicketRecord>,
+    pub backlog: Option<&'a SpillQueue>,
+}
+
+impl Serialize for PendingTickets<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(None)?;
+        for ticket in &self.queued {
+            seq.serialize_element(ticket)?;
+        }
+        for ticket in self.backlog.into_iter().flat_map(SpillQueue::iter) {
+            let ticket = ticket.map_err(|e| serde::ser::Error::custom(format!("{e:#}")))?;
+            seq.serialize_element(&ticket)?;
+        }
+        seq.end()
+    }
+}
+
+/// Write-ahead log of observations plus periodic snapshots of the collector state.
+///
+/// Every observation is appended to the journal before the collector acts on it, so none is lost
+/// when speedd crashes. The journal only reaches the disk on [`Storage::sync`], so a crash of the
+/// machine loses the observations appended since, see [`crate::config::Config::journal_sync`].
+/// Taking a snapshot replaces the snapshot file atomically and truncates the journal, syncing both
+/// to disk.
+/// On startup, the snapshot is loaded and the journal is replayed on top of it.
+#[derive(Debug)]
+pub struct Storage {
+    dir: PathBuf,
+    journal: JsonlWriter<File>,
+    entries: usize,
+    /// Whether observations were appended since the journal was last synced
+    unsynced: bool,
+}
+
+impl Storage {
+    /// Opens (or creates) the storage directory and returns the last snapshot
+    /// together with the observations journaled after it.
+    pub fn open(dir: impl AsRef<Path>) -> anyhow::Result<(Self, Snapshot, Vec<Observation>)> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create storage directory {dir:?}"))?;
+
+        let snapshot_path = dir.join(SNAPSHOT_FILE);
+        let snapshot = if snapshot_path.exists() {
+            let file = File::open(&snapshot_path).context("Failed to open snapshot")?;
+            serde_json::from_reader(BufReader::new(file)).context("Failed to parse snapshot")?
+        } else {
+            Snapshot::default()
+        };
+
+        let journal_path = dir.join(JOURNAL_FILE);
+        let observations = if journal_path.exists() {
+            read_observations(&journal_path)?
+        } else {
+            Vec::new()
+        };
+        tracing::info!(
+            "Restored snapshot of {} plates and {} journaled observations from {dir:?}",
+            snapshot.records.len(),
+            observations.len()
+        );
+
+        let journal = JsonlWriter::new(jsonl::open_append(&journal_path, "journal")?);
+        let entries = observations.len();
+        Ok((
+            Self {
+                dir,
+                journal,
+                entries,
+                unsynced: false,
+            },
+            snapshot,
+            observations,
+        ))
+    }
+
+    /// Appends an observation to the journal.
+    pub fn append(&mut self, record: &PlateRecord, camera: &Camera) -> anyhow::Result<()> {
+        self.journal
+            .write(&(record, camera))
+            .context("Failed to append to journal")?;
+        self.entries += 1;
+        self.unsynced = true;
+        Ok(())
+    }
+
+    /// Whether observations were appended since the journal was last synced to disk.
+    pub fn unsynced(&self) -> bool {
+        self.unsynced
+    }
+
+    /// Syncs the observations appended so far to disk.
+    pub fn sync(&mut self) -> anyhow::Result<()> {
+        if self.unsynced {
+            let journal = self.journal.get_ref();
+            journal.sync_data().context("Failed to sync journal")?;
+            self.unsynced = false;
+        }
+        Ok(())
+    }
+
+    /// Number of observations journaled since the last snapshot.
+    pub fn entries(&self) -> usize {
+        self.entries
+    }
+
+    /// Atomically replaces the snapshot and truncates the journal.
+    pub fn snapshot(&mut self, snapshot: &SnapshotRef) -> anyhow::Result<()> {
+        let path = self.dir.join(SNAPSHOT_FILE);
+        let tmp = path.with_extension("json.tmp");
+        {
+            let mut file = BufWriter::new(File::create(&tmp).context("Failed to create snapshot")?);
+            serde_json::to_writer(&mut file, snapshot).context("Failed to write snapshot")?;
+            let file = file.into_inner().context("Failed to write snapshot")?;
+            file.sync_all().context("Failed to sync snapshot")?;
+        }
+        fs::rename(&tmp, &path).context("Failed to replace snapshot")?;
+        // The rename must be on disk before the journal is gone
+        #[cfg(unix)]
+        File::open(&self.dir)
+            .and_then(|dir| dir.sync_all())
+            .context("Failed to sync storage directory")?;
+        // Otherwise the old entries could come back after a crash and be replayed twice
+        let journal = self.journal.get_ref();
+        journal.set_len(0).context("Failed to truncate journal")?;
+        journal.sync_all().context("Failed to sync journal")?;
+        self.entries = 0;
+        self.unsynced = false;
+        tracing::info!("Wrote snapshot to {path:?}");
+        Ok(())
+    }
+}
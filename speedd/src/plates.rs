@@ -0,0 +1,101 @@
+use crate::config;
+use std::fmt;
+
+/// Turns plate reads into the keys the collectors group their records by, so differing reads of
+/// the same plate end up in one history. Cameras drop the reads which fail validation, and
+/// tickets still name the plate as it was read.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PlateRules {
+    config: config::Plates,
+}
+
+/// Why a plate read was rejected.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Rejection {
+    TooShort(usize),
+    TooLong(usize),
+    NotAlphanumeric(char),
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TooShort(min) => write!(f, "shorter than {min} characters"),
+            Self::TooLong(max) => write!(f, "longer than {max} characters"),
+            Self::NotAlphanumeric(c) => write!(f, "{c:?} is neither a letter nor a digit"),
+        }
+    }
+}
+
+impl PlateRules {
+    pub fn new(config: config::Plates) -> Self {
+        Self { config }
+    }
+
+    /// Normalizes a plate read, then validates the result.
+    pub fn normalize(&self, plate: &str) -> Result<String, Rejection> {
+        let plate = plate
+            .chars()
+            .filter(|c| !self.config.strip || c.is_ascii_alphanumeric())
+            .map(|c| {
+                if self.config.uppercase {
+                    c.to_ascii_uppercase()
+                } else {
+                    c
+                }
+            })
+            .map(|c| match c {
+                'O' | 'o' if self.config.ocr_confusions => '0',
+                'I' | 'i' if self.config.ocr_confusions => '1',
+                'B' | 'b' if self.config.ocr_confusions => '8',
+                c => c,
+            })
+            .collect::<String>();
+        let len = plate.chars().count();
+        if len < self.config.min_length {
+            return Err(Rejection::TooShort(self.config.min_length));
+        }
+        if let Some(max) = self.config.max_length.filter(|max| len > *max) {
+            return Err(Rejection::TooLong(max));
+        }
+        if self.config.alphanumeric {
+            if let Some(c) = plate.chars().find(|c| !c.is_ascii_alphanumeric()) {
+                return Err(Rejection::NotAlphanumeric(c));
+            }
+        }
+        Ok(plate)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn normalizes_and_validates() {
+        let spec = PlateRules::default();
+        assert_eq!(spec.normalize("un1x-7"), Ok("un1x-7".to_string()));
+        assert_eq!(spec.normalize(""), Err(Rejection::TooShort(1)));
+
+        let rules = PlateRules::new(config::Plates {
+            uppercase: true,
+            strip: true,
+            ocr_confusions: true,
+            min_length: 2,
+            max_length: Some(7),
+            alphanumeric: true,
+        });
+        for read in ["RE05BKG", "re 05 bkg", "RE-O5-8KG", "rEo5bKg"] {
+            assert_eq!(rules.normalize(read), Ok("RE058KG".to_string()), "{read}");
+        }
+        assert_eq!(rules.normalize("I-"), Err(Rejection::TooShort(2)));
+        assert_eq!(rules.normalize("ABCD1234"), Err(Rejection::TooLong(7)));
+
+        let unstripped = PlateRules::new(config::Plates {
+            alphanumeric: true,
+            ..config::Plates::default()
+        });
+        let rejection = unstripped.normalize("ÜN1X").unwrap_err();
+        assert_eq!(rejection.to_string(), "'Ü' is neither a letter nor a digit");
+    }
+}
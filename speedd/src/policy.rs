@@ -0,0 +1,159 @@
+use crate::config::{self, PolicyKind};
+use speedd_codecs::{Limit, Mile, Road, Timestamp};
+use std::{collections::HashMap, fmt::Debug, sync::Arc};
+
+/// Decides whether a car travelling between two observations on a road broke the speed limit.
+pub trait ViolationPolicy: Debug + Send + Sync {
+    /// Returns the average speed to put on the ticket, in hundredths of mph, if this is a violation.
+    /// `limit` is in mph, `from` and `to` are the timestamps and miles of the observations in time order.
+    fn violation(
+        &self,
+        road: Road,
+        limit: Limit,
+        from: (Timestamp, Mile),
+        to: (Timestamp, Mile),
+    ) -> Option<u16>;
+}
+
+/// Builds the policy described in the configuration.
+pub fn from_config(config: &config::Policy) -> Arc<dyn ViolationPolicy> {
+    let roads = config
+        .roads
+        .iter()
+        .map(|road| (road.road, kind(road.kind, road.tolerance)))
+        .collect::<HashMap<_, _>>();
+    let default = kind(config.kind, config.tolerance);
+    if roads.is_empty() {
+        Arc::from(default)
+    } else {
+        Arc::new(PerRoad { default, roads })
+    }
+}
+
+fn kind(kind: PolicyKind, tolerance: u16) -> Box<dyn ViolationPolicy> {
+    match kind {
+        PolicyKind::Rounded => Box::new(Rounded),
+        PolicyKind::Exact => Box::new(Exact { tolerance }),
+    }
+}
+
+/// The speed rounded to whole mph must exceed the limit, which is what the spec asks for:
+/// cars going at least half a mph too fast get a ticket.
+/// Computed in `f32`, with tickets and limits saturating at about 655 mph.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Rounded;
+
+impl ViolationPolicy for Rounded {
+    fn violation(
+        &self,
+        _road: Road,
+        limit: Limit,
+        (ts1, mile1): (Timestamp, Mile),
+        (ts2, mile2): (Timestamp, Mile),
+    ) -> Option<u16> {
+        let delta_t = ts1.abs_diff(ts2);
+        let delta_m = mile1.abs_diff(mile2);
+        let speed = (delta_m as f32 / delta_t as f32) * 60.0 * 60.0;
+        let speed = speed.round() as u16;
+        let speed = speed.saturating_mul(100);
+        if speed > limit.saturating_mul(100) {
+            Some(speed)
+        } else {
+            None
+        }
+    }
+}
+
+/// The exact average speed must exceed the limit by more than `tolerance` hundredths of mph.
+/// Uses integer arithmetic only. The speed on the ticket is rounded to hundredths of mph and is the
+/// only thing which saturates, at 655.35 mph. Observations at the same time are never a violation.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Exact {
+    pub tolerance: u16,
+}
+
+impl ViolationPolicy for Exact {
+    fn violation(
+        &self,
+        _road: Road,
+        limit: Limit,
+        (ts1, mile1): (Timestamp, Mile),
+        (ts2, mile2): (Timestamp, Mile),
+    ) -> Option<u16> {
+        let delta_t = u64::from(ts1.abs_diff(ts2));
+        if delta_t == 0 {
+            return None;
+        }
+        // Miles per second to hundredths of mph
+        let distance = u64::from(mile1.abs_diff(mile2)) * 60 * 60 * 100;
+        let allowed = u64::from(limit) * 100 + u64::from(self.tolerance);
+        if distance > allowed * delta_t {
+            let speed = (2 * distance + delta_t) / (2 * delta_t);
+            Some(u16::try_from(speed).unwrap_or(u16::MAX))
+        } else {
+            None
+        }
+    }
+}
+
+/// Applies a different policy to some roads.
+#[derive(Debug)]
+pub struct PerRoad {
+    pub default: Box<dyn ViolationPolicy>,
+    pub roads: HashMap<Road, Box<dyn ViolationPolicy>>,
+}
+
+impl ViolationPolicy for PerRoad {
+    fn violation(
+        &self,
+        road: Road,
+        limit: Limit,
+        from: (Timestamp, Mile),
+        to: (Timestamp, Mile),
+    ) -> Option<u16> {
+        self.roads
+            .get(&road)
+            .unwrap_or(&self.default)
+            .violation(road, limit, from, to)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::RoadPolicy;
+
+    #[test]
+    fn policies() {
+        // 60 mph limit, 0.5 mph too fast: 121 miles in two hours
+        let (from, to) = ((0, 0), (7200, 121));
+        assert_eq!(Rounded.violation(1, 60, from, to), Some(6100));
+        assert_eq!(
+            Exact { tolerance: 0 }.violation(1, 60, from, to),
+            Some(6050)
+        );
+        assert_eq!(Exact { tolerance: 50 }.violation(1, 60, from, to), None);
+        assert_eq!(
+            Exact { tolerance: 49 }.violation(1, 60, from, to),
+            Some(6050)
+        );
+
+        // 1000 mph on a 700 mph road
+        let (from, to) = ((0, 0), (36, 10));
+        assert_eq!(Rounded.violation(1, 700, from, to), None);
+        assert_eq!(Exact::default().violation(1, 700, from, to), Some(u16::MAX));
+
+        let policy = from_config(&config::Policy {
+            kind: PolicyKind::Exact,
+            tolerance: 0,
+            roads: vec![RoadPolicy {
+                road: 2,
+                kind: PolicyKind::Exact,
+                tolerance: 100,
+            }],
+        });
+        let (from, to) = ((0, 0), (3600, 61));
+        assert_eq!(policy.violation(1, 60, from, to), Some(6100));
+        assert_eq!(policy.violation(2, 60, from, to), None);
+    }
+}
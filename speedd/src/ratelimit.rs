@@ -0,0 +1,72 @@
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Allows `rate` events per second on average, and bursts of up to `burst` events.
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// Starts with a full bucket.
+    pub fn new(rate: u32, burst: u32) -> Self {
+        Self {
+            rate: f64::from(rate),
+            burst: f64::from(burst),
+            tokens: f64::from(burst),
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.updated = now;
+    }
+
+    /// Uses up a token, if there is one.
+    pub fn take(&mut self) {
+        self.tokens = (self.tokens - 1.0).max(0.0);
+    }
+
+    /// When the next token will be there, or `None` if there is one already.
+    pub fn next_token(&mut self) -> Option<Instant> {
+        self.refill();
+        let missing = 1.0 - self.tokens;
+        (missing > 0.0).then(|| self.updated + Duration::from_secs_f64(missing / self.rate))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn refills_at_rate() {
+        let mut bucket = TokenBucket::new(10, 3);
+        for _ in 0..3 {
+            assert!(bucket.next_token().is_none());
+            bucket.take();
+        }
+        assert!(bucket.next_token().is_some());
+
+        let start = Instant::now();
+        tokio::time::sleep_until(bucket.next_token().unwrap()).await;
+        assert_eq!(start.elapsed(), Duration::from_millis(100));
+        assert!(bucket.next_token().is_none());
+        bucket.take();
+        assert!(bucket.next_token().is_some());
+
+        // Never more than the burst
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        for _ in 0..3 {
+            assert!(bucket.next_token().is_none());
+            bucket.take();
+        }
+        assert!(bucket.next_token().is_some());
+    }
+}
@@ -0,0 +1,859 @@
+use crate::{
+    admin::Admin,
+    audit::AuditLog,
+    camera::CameraClient,
+    cameras::Cameras,
+    capture::Capture,
+    client::{self, Action},
+    collector::Collector,
+    config::Config,
+    dispatcher::Dispatcher,
+    fines::Tariff,
+    heartbeat::Heartbeats,
+    metrics::METRICS,
+    persistence,
+    plates::PlateRules,
+    policy,
+    sessions::{Role, SessionGuard, Sessions},
+    shards::Shards,
+    sinks::{self, Sinks},
+    tls,
+};
+use anyhow::Context;
+use futures::{future::BoxFuture, FutureExt, Sink, SinkExt, Stream, StreamExt};
+use speedd_codecs::client::decoder::MessageDecoder;
+use speedd_codecs::client::Message as ClientMessage;
+use speedd_codecs::{server, Road};
+use std::{collections::BTreeMap, fmt, future::Future, net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+    task::JoinHandle,
+    time::Instant,
+};
+use tokio_rustls::TlsAcceptor;
+use tokio_util::{
+    codec::{FramedRead, FramedWrite},
+    sync::CancellationToken,
+    task::TaskTracker,
+};
+
+/// How often to check whether the ticket queues are drained and the connections closed when shutting down.
+const SHUTDOWN_POLL: Duration = Duration::from_millis(100);
+
+/// Time the connections get to close after draining.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A speed camera server, with its collector shards, admin interface and accept loop.
+///
+/// ```no_run
+/// # async fn example() -> anyhow::Result<()> {
+/// let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+/// let server = speedd::Server::builder()
+///     .with_address("127.0.0.1:0".parse()?)
+///     .with_shutdown(async move {
+///         stopped.await.ok();
+///     })
+///     .start()
+///     .await?;
+/// println!("Listening on {}", server.local_addr());
+/// stop.send(()).ok();
+/// println!("{}", server.wait().await?);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct Server;
+
+impl Server {
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::default()
+    }
+}
+
+#[derive(Default)]
+pub struct ServerBuilder {
+    config: Config,
+    listener: Option<TcpListener>,
+    shutdown: Option<BoxFuture<'static, ()>>,
+}
+
+impl ServerBuilder {
+    pub fn with_config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Listens on `address` instead of the configured address.
+    pub fn with_address(mut self, address: SocketAddr) -> Self {
+        self.config.address = address;
+        self
+    }
+
+    /// Accepts connections on an already bound listener, ignoring the configured address.
+    pub fn with_listener(mut self, listener: TcpListener) -> Self {
+        self.listener = Some(listener);
+        self
+    }
+
+    /// Shuts down once `shutdown` completes. Without it the server runs forever.
+    ///
+    /// A shutdown stops accepting connections and disconnects all clients but the dispatchers.
+    /// These get `shutdown.drain_timeout` seconds to pick up the queued tickets. Then they are
+    /// disconnected as well, the admin interface stops, and the collectors save the remaining tickets with their storage,
+    /// or in the `shutdown.backlog` file.
+    pub fn with_shutdown(mut self, shutdown: impl Future<Output = ()> + Send + 'static) -> Self {
+        self.shutdown = Some(shutdown.boxed());
+        self
+    }
+
+    /// Validates the configuration, binds the listeners, loads the storage and spawns the server.
+    pub async fn start(self) -> anyhow::Result<ServerHandle> {
+        let config = self.config;
+        config.validate().context("Invalid configuration")?;
+        let listener = match self.listener {
+            Some(listener) => listener,
+            None => TcpListener::bind(config.address)
+                .await
+                .with_context(|| format!("Failed to bind {}", config.address))?,
+        };
+        let local_addr = listener.local_addr()?;
+
+        let services = Services::new(config)?;
+        // Stops when the dispatchers are disconnected, after the queues could be watched draining
+        let (admin_addr, admin) = match services.config.admin {
+            Some(admin_addr) => {
+                let admin_listener = TcpListener::bind(admin_addr).await?;
+                let admin_addr = admin_listener.local_addr()?;
+                let admin = Admin::new(
+                    services.shards.clone(),
+                    services.sessions.clone(),
+                    services.cameras.clone(),
+                )
+                .with_plate_rules(services.plates.clone());
+                let shutdown = services.closing.clone().cancelled_owned();
+                let admin = tokio::spawn(admin.serve(admin_listener, shutdown));
+                (Some(admin_addr), Some(admin))
+            }
+            None => (None, None),
+        };
+
+        let acceptor = services
+            .config
+            .tls
+            .as_ref()
+            .map(tls::acceptor)
+            .transpose()?;
+        let accept_loop = AcceptLoop {
+            listener,
+            acceptor,
+            services,
+            admin,
+        };
+        let shutdown = self
+            .shutdown
+            .unwrap_or_else(|| futures::future::pending().boxed());
+        Ok(ServerHandle {
+            local_addr,
+            admin_addr,
+            task: tokio::spawn(accept_loop.run(shutdown)),
+        })
+    }
+}
+
+/// A running server.
+#[derive(Debug)]
+pub struct ServerHandle {
+    local_addr: SocketAddr,
+    admin_addr: Option<SocketAddr>,
+    task: JoinHandle<anyhow::Result<ShutdownSummary>>,
+}
+
+impl ServerHandle {
+    /// The address the server accepts connections on, with the actual port if it was bound to port 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// The address of the admin interface, if it is enabled.
+    pub fn admin_addr(&self) -> Option<SocketAddr> {
+        self.admin_addr
+    }
+
+    /// Waits until the server has shut down after its shutdown signal.
+    pub async fn wait(self) -> anyhow::Result<ShutdownSummary> {
+        self.task.await?
+    }
+}
+
+/// The tickets a server could not deliver before it shut down.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ShutdownSummary {
+    /// Number of undelivered tickets per road
+    pub undelivered: BTreeMap<Road, usize>,
+}
+
+impl ShutdownSummary {
+    pub fn total(&self) -> usize {
+        self.undelivered.values().sum()
+    }
+}
+
+impl fmt::Display for ShutdownSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.undelivered.is_empty() {
+            return write!(f, "All tickets were delivered");
+        }
+        write!(f, "{} undelivered tickets:", self.total())?;
+        for (road, tickets) in &self.undelivered {
+            write!(f, " {tickets} for road {road}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Everything connections are served with: the collector shards, the registries of connected
+/// clients, the heartbeat scheduler, the configuration and the tokens of the shutdown phases.
+/// The tasks feeding the ticket sinks are tracked to let them finish when shutting down.
+/// With a capture, every decoded client message is recorded.
+#[derive(Clone, Debug)]
+pub(crate) struct Services {
+    pub shards: Shards,
+    pub sessions: Sessions,
+    pub cameras: Cameras,
+    pub heartbeats: Heartbeats,
+    pub config: Arc<Config>,
+    /// Cancelled when shutting down, disconnects all clients but the dispatchers
+    pub draining: CancellationToken,
+    /// Cancelled when the tickets are drained, disconnects the dispatchers
+    pub closing: CancellationToken,
+    pub sink_tasks: TaskTracker,
+    /// How the cameras' plate reads are validated and normalized
+    pub plates: PlateRules,
+    pub capture: Option<Capture>,
+}
+
+impl Services {
+    /// Loads the storage and spawns the collector shards.
+    pub fn new(config: Config) -> anyhow::Result<Self> {
+        let storage = match &config.storage {
+            Some(dir) => Some(persistence::shard_dirs(dir, config.shards)?),
+            None => None,
+        };
+        let audit = config.audit_log.as_ref().map(AuditLog::open).transpose()?;
+        let capture = config.capture.as_ref().map(Capture::create).transpose()?;
+        let policy = policy::from_config(&config.policy);
+        let tariff = Tariff::new(config.tariff.clone());
+        let plates = PlateRules::new(config.plates.clone());
+        let sink_tasks = TaskTracker::new();
+        let sinks = Sinks::spawn(
+            sinks::from_config(&config.sinks)?,
+            config.queues.sinks,
+            &sink_tasks,
+        );
+        let collectors = (0..config.shards)
+            .map(|shard| {
+                let mut collector = Collector::new()
+                    .with_ticket_queue_capacity(config.queues.tickets)
+                    .with_spilling(config.queues.spill_threshold, config.queues.spill_dir())
+                    .with_seconds_per_day(config.seconds_per_day)
+                    .with_journal_sync(config.journal_sync())
+                    .with_policy(policy.clone())
+                    .with_tariff(tariff.clone())
+                    .with_plate_rules(plates.clone());
+                if let Some(days) = config.retention_days {
+                    collector = collector.with_retention_days(days);
+                }
+                let collector = match &storage {
+                    Some(dirs) => collector.with_storage(&dirs[shard])?,
+                    None => collector,
+                };
+                let collector = match &audit {
+                    Some(audit) => collector.with_audit_log(audit.clone()),
+                    None => collector,
+                };
+                Ok(collector.with_sinks(sinks.clone()))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        tracing::info!("Starting {} collector shards", collectors.len());
+        let shards = Shards::spawn(collectors, &config.queues).with_plate_rules(plates.clone());
+        if let (Some(backlog), Some(consumed)) =
+            (&config.shutdown.backlog, config.shutdown.consumed_backlog())
+        {
+            // A backlog left by a clean shutdown supersedes the one consumed before it
+            if backlog.exists() {
+                std::fs::rename(backlog, &consumed)
+                    .with_context(|| format!("Failed to move {backlog:?} to {consumed:?}"))?;
+            }
+            if consumed.exists() {
+                let tickets = persistence::read_tickets(&consumed)?;
+                tracing::info!("Requeueing {} tickets from {consumed:?}", tickets.len());
+                for ticket in tickets {
+                    shards.requeue(ticket);
+                }
+            }
+        }
+
+        let mut sessions = Sessions::default();
+        if let Some(limit) = config.limits.connections_per_ip {
+            sessions = sessions.with_connections_per_ip(limit);
+        }
+        let cameras = Cameras::new(config.camera_conflicts)
+            .with_health(config.health.silence(), config.health.max_skew());
+        Ok(Self {
+            shards,
+            sessions,
+            cameras,
+            heartbeats: Heartbeats::default(),
+            config: Arc::new(config),
+            draining: CancellationToken::new(),
+            closing: CancellationToken::new(),
+            sink_tasks,
+            plates,
+            capture,
+        })
+    }
+
+    /// Lets the dispatchers drain the ticket queues, disconnects everybody, stops the collectors and
+    /// lets the ticket sinks catch up.
+    /// Saves the tickets which are still undelivered to the backlog, if there is one, and only then
+    /// removes the backlog consumed on startup.
+    pub async fn shut_down(&self) -> anyhow::Result<ShutdownSummary> {
+        let drain_timeout = self.config.shutdown.drain_timeout();
+        tracing::info!("Shutting down, draining ticket queues for up to {drain_timeout:?}");
+        self.draining.cancel();
+        let deadline = Instant::now() + drain_timeout;
+        loop {
+            let queued = self
+                .shards
+                .roads()
+                .await?
+                .iter()
+                .filter(|road| road.dispatchers > 0)
+                .map(|road| road.queued_tickets)
+                .sum::<usize>();
+            if queued == 0 {
+                break;
+            }
+            if Instant::now() >= deadline {
+                tracing::warn!("{queued} tickets are still queued for connected dispatchers");
+                break;
+            }
+            tokio::time::sleep_until(deadline.min(Instant::now() + SHUTDOWN_POLL)).await;
+        }
+
+        // Dispatchers requeue the tickets they did not deliver when they go away
+        self.closing.cancel();
+        let closed = async {
+            while !self.sessions.list().is_empty() {
+                tokio::time::sleep(SHUTDOWN_POLL).await;
+            }
+        };
+        if tokio::time::timeout(CLOSE_TIMEOUT, closed).await.is_err() {
+            tracing::warn!("{} connections did not close", self.sessions.list().len());
+        }
+
+        let tickets = self.shards.shutdown().await?;
+        // The collectors are gone, and with them the senders to the sinks
+        self.sink_tasks.close();
+        if tokio::time::timeout(CLOSE_TIMEOUT, self.sink_tasks.wait())
+            .await
+            .is_err()
+        {
+            tracing::warn!("Ticket sinks did not catch up with the issued tickets");
+        }
+        if let Some(backlog) = &self.config.shutdown.backlog {
+            if !tickets.is_empty() {
+                persistence::write_tickets(backlog, &tickets)?;
+                tracing::info!("Saved {} undelivered tickets to {backlog:?}", tickets.len());
+            }
+        }
+        if let Some(consumed) = self
+            .config
+            .shutdown
+            .consumed_backlog()
+            .filter(|path| path.exists())
+        {
+            std::fs::remove_file(&consumed)
+                .with_context(|| format!("Failed to remove {consumed:?}"))?;
+        }
+        let mut summary = ShutdownSummary::default();
+        for ticket in &tickets {
+            *summary.undelivered.entry(ticket.road).or_default() += 1;
+        }
+        Ok(summary)
+    }
+
+    /// Speaks the protocol on an accepted plain or TLS stream, in a new task.
+    pub fn serve<S>(&self, stream: S, session: SessionGuard) -> JoinHandle<anyhow::Result<()>>
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        tokio::spawn(serve_connection(stream, self.clone(), session))
+    }
+}
+
+struct AcceptLoop {
+    listener: TcpListener,
+    acceptor: Option<TlsAcceptor>,
+    services: Services,
+    /// The admin interface, which shuts down along with the dispatchers
+    admin: Option<JoinHandle<anyhow::Result<()>>>,
+}
+
+impl AcceptLoop {
+    async fn run(self, mut shutdown: BoxFuture<'static, ()>) -> anyhow::Result<ShutdownSummary> {
+        tracing::info!("Accepting connections on {}", self.listener.local_addr()?);
+        loop {
+            let (inbound, addr) = tokio::select! {
+                accepted = self.listener.accept() => accepted?,
+                () = &mut shutdown => {
+                    drop(self.listener);
+                    let summary = self.services.shut_down().await?;
+                    if let Some(mut admin) = self.admin {
+                        match tokio::time::timeout(CLOSE_TIMEOUT, &mut admin).await {
+                            Ok(served) => served??,
+                            Err(_) => {
+                                tracing::warn!("Admin requests did not finish, aborting them");
+                                admin.abort();
+                            }
+                        }
+                    }
+                    if summary.undelivered.is_empty() {
+                        tracing::info!("{summary}");
+                    } else {
+                        tracing::warn!("{summary}");
+                    }
+                    return Ok(summary);
+                }
+            };
+            tracing::info!("Accepted connection from {addr}");
+            let Some(session) = self.services.sessions.register(addr) else {
+                tracing::warn!("Refusing connection from {addr}, its address has too many already");
+                METRICS.connections_refused.inc();
+                // A TLS client would not understand the plain error message
+                if self.acceptor.is_none() {
+                    let mut writer = FramedWrite::new(inbound, server::encoder::MessageEncoder);
+                    tokio::spawn(async move {
+                        writer
+                            .send(server::Message::Error("Too many connections".to_string()))
+                            .await
+                    });
+                }
+                continue;
+            };
+            let Some(acceptor) = self.acceptor.clone() else {
+                self.services.serve(inbound, session);
+                continue;
+            };
+            let services = self.services.clone();
+            tokio::spawn(async move {
+                // The handshake is part of identifying
+                let handshake = acceptor.accept(inbound);
+                let stream = match services.config.timeouts.identification() {
+                    Some(timeout) => tokio::time::timeout(timeout, handshake)
+                        .await
+                        .unwrap_or_else(|_| Err(std::io::ErrorKind::TimedOut.into())),
+                    None => handshake.await,
+                };
+                match stream {
+                    Ok(stream) => serve_connection(stream, services, session).await,
+                    Err(e) => {
+                        tracing::warn!("TLS handshake with {addr} failed: {e}");
+                        Ok(())
+                    }
+                }
+            });
+        }
+    }
+}
+
+/// Speaks the protocol on a plain or TLS stream.
+async fn serve_connection<S>(
+    stream: S,
+    services: Services,
+    session: SessionGuard,
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (reader, writer) = tokio::io::split(stream);
+    let reader = FramedRead::new(reader, MessageDecoder);
+    let writer = FramedWrite::new(writer, server::encoder::MessageEncoder);
+    match &services.capture {
+        Some(capture) => {
+            let mut capture = capture.connection(session.id());
+            let reader = reader.inspect(move |msg| {
+                if let Ok(msg) = msg {
+                    capture.record(msg);
+                }
+            });
+            handle_connection(reader, writer, services, session).await
+        }
+        None => handle_connection(reader, writer, services, session).await,
+    }
+}
+
+async fn handle_connection<R, W>(
+    mut reader: R,
+    writer: W,
+    services: Services,
+    session: SessionGuard,
+) -> anyhow::Result<()>
+where
+    R: Stream<Item = Result<ClientMessage, anyhow::Error>> + Send + Unpin,
+    W: Sink<server::Message, Error = anyhow::Error> + Send + Unpin + 'static,
+{
+    let Services {
+        shards,
+        cameras,
+        heartbeats,
+        config,
+        draining,
+        closing,
+        plates,
+        ..
+    } = services;
+    let (mut writer, mut heartbeat) = heartbeats.connection(writer);
+
+    let identification_deadline =
+        tokio::time::Instant::now() + config.timeouts.identification().unwrap_or_default();
+
+    tracing::info!("Entering client connection loop");
+    loop {
+        tokio::select! {
+            Some(msg) = reader.next() => {
+                match msg {
+                    Ok(msg) => {
+                        let action = client::action(msg, &mut heartbeat);
+                        match action {
+                            Action::None => {},
+                            Action::Error(r) => {
+                                writer.send(r).await?;
+                                if config.strict {
+                                    break;
+                                }
+                            }
+                            Action::SpawnCamera(c) => {
+                                let camera = match cameras.register(session.id(), c) {
+                                    Ok(camera) => camera,
+                                    Err(conflict) => {
+                                        writer.send(server::Message::Error(conflict.to_string())).await?;
+                                        break;
+                                    }
+                                };
+                                session.set_role(Role::Camera(camera.camera().clone()));
+                                let mut client = CameraClient::new(camera.camera().clone())
+                                    .with_strict(config.strict)
+                                    .with_shutdown(draining)
+                                    .with_plate_rules(plates)
+                                    .with_registration(camera);
+                                if let Some(idle) = config.timeouts.idle() {
+                                    client = client.with_idle_timeout(idle);
+                                }
+                                if let Some(rate) = config.limits.plates_per_second {
+                                    client = client.with_plate_limit(rate, config.limits.plate_burst.unwrap_or(rate));
+                                }
+                                CameraClient::run(client, reader, writer, shards, heartbeat).await?;
+                                break;
+                            }
+                            Action::SpawnDispatcher(r) => {
+                                session.set_role(Role::Dispatcher(r.clone()));
+                                let dispatcher = Dispatcher::new(&r, &shards)
+                                    .await?
+                                    .with_strict(config.strict)
+                                    .with_ack_window(config.queues.unacked)
+                                    .with_shutdown(closing);
+                                Dispatcher::run(dispatcher, reader, writer, heartbeat).await?;
+                                break;
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        METRICS.decode_errors.inc();
+                        writer.send(server::Message::Error(format!("... who even are you? {e}"))).await?;
+                        if config.strict {
+                            break;
+                        }
+                    }
+                }
+            }
+            () = draining.cancelled() => {
+                writer.send(server::Message::Error("Server is shutting down".to_string())).await?;
+                break;
+            }
+            _ = tokio::time::sleep_until(identification_deadline), if config.timeouts.identification.is_some() => {
+                tracing::info!("Disconnecting client which did not identify in time");
+                writer.send(server::Message::Error("You took too long to identify".to_string())).await?;
+                break;
+            }
+            else => break,
+        }
+    }
+    tracing::info!("Leaving client connection loop");
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::{self, Timeouts};
+    use speedd_codecs::{camera::Camera, client::encoder::MessageEncoder, plate::PlateRecord};
+    use std::time::Duration;
+    use tokio::io::{AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf};
+
+    type ClientWriter = FramedWrite<WriteHalf<DuplexStream>, MessageEncoder>;
+    type ClientReader = FramedRead<ReadHalf<DuplexStream>, server::decoder::MessageDecoder>;
+
+    /// Serves a single connection over an in-memory stream, like the accept loop does.
+    fn connect(config: Config) -> (ClientWriter, ClientReader) {
+        let (client, server) = tokio::io::duplex(1024);
+        let services = Services::new(config).unwrap();
+        let session = services
+            .sessions
+            .register("127.0.0.1:1234".parse().unwrap())
+            .unwrap();
+        services.serve(server, session);
+        let (reader, writer) = tokio::io::split(client);
+        (
+            FramedWrite::new(writer, MessageEncoder),
+            FramedRead::new(reader, server::decoder::MessageDecoder),
+        )
+    }
+
+    async fn next_error<R: AsyncRead + Unpin>(
+        reader: &mut FramedRead<R, server::decoder::MessageDecoder>,
+    ) -> String {
+        match reader.next().await {
+            Some(Ok(server::Message::Error(error))) => error,
+            other => panic!("Expected an error, got {other:?}"),
+        }
+    }
+
+    /// Sends the `illegal` message after the `prelude`, in strict and in lenient mode.
+    /// Expects an error each time, and the end of the connection only in strict mode.
+    async fn assert_illegal(prelude: &[ClientMessage], illegal: ClientMessage) {
+        let (mut writer, mut reader) = connect(Config::default());
+        for msg in prelude {
+            writer.send(msg.clone()).await.unwrap();
+        }
+        writer.send(illegal.clone()).await.unwrap();
+        let error = next_error(&mut reader).await;
+        assert!(
+            reader.next().await.is_none(),
+            "Still connected after {illegal:?}: {error}"
+        );
+
+        let (mut writer, mut reader) = connect(Config {
+            strict: false,
+            ..Config::default()
+        });
+        for msg in prelude {
+            writer.send(msg.clone()).await.unwrap();
+        }
+        writer.send(illegal.clone()).await.unwrap();
+        writer.send(illegal).await.unwrap();
+        next_error(&mut reader).await;
+        next_error(&mut reader).await;
+    }
+
+    fn camera() -> ClientMessage {
+        ClientMessage::IAmCamera(Camera {
+            road: 1,
+            mile: 2,
+            limit: 60,
+        })
+    }
+
+    fn dispatcher() -> ClientMessage {
+        ClientMessage::IAmDispatcher(vec![1])
+    }
+
+    fn plate() -> ClientMessage {
+        ClientMessage::Plate(PlateRecord {
+            plate: "ABC".to_string(),
+            timestamp: 1,
+        })
+    }
+
+    fn heartbeat() -> ClientMessage {
+        ClientMessage::WantHeartbeat(Duration::ZERO)
+    }
+
+    #[tokio::test]
+    async fn illegal_messages_of_unidentified_clients() {
+        assert_illegal(&[], plate()).await;
+        assert_illegal(&[heartbeat()], heartbeat()).await;
+        assert_illegal(&[], ClientMessage::WantAcks).await;
+        assert_illegal(&[], ClientMessage::Ack(1)).await;
+    }
+
+    #[tokio::test]
+    async fn illegal_messages_of_cameras() {
+        assert_illegal(&[camera()], camera()).await;
+        assert_illegal(&[camera()], dispatcher()).await;
+        assert_illegal(&[camera(), heartbeat()], heartbeat()).await;
+        assert_illegal(&[heartbeat(), camera()], heartbeat()).await;
+        assert_illegal(&[camera()], ClientMessage::WantAcks).await;
+        assert_illegal(&[camera()], ClientMessage::Ack(1)).await;
+    }
+
+    #[tokio::test]
+    async fn illegal_messages_of_dispatchers() {
+        assert_illegal(&[dispatcher()], plate()).await;
+        assert_illegal(&[dispatcher()], camera()).await;
+        assert_illegal(&[dispatcher()], dispatcher()).await;
+        assert_illegal(&[dispatcher(), heartbeat()], heartbeat()).await;
+        assert_illegal(&[dispatcher()], ClientMessage::Ack(1)).await;
+        assert_illegal(
+            &[dispatcher(), ClientMessage::WantAcks],
+            ClientMessage::Ack(1),
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn undecodable_messages_disconnect() {
+        for prelude in [vec![], vec![camera()], vec![dispatcher()]] {
+            let (mut writer, mut reader) = connect(Config::default());
+            for msg in prelude {
+                writer.send(msg).await.unwrap();
+            }
+            writer.get_mut().write_all(&[0xff]).await.unwrap();
+            next_error(&mut reader).await;
+            let next = reader.next().await;
+            assert!(next.is_none(), "{next:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn serves_tls() {
+        let dir = tempfile::tempdir().unwrap();
+        let rcgen::CertifiedKey { cert, key_pair } =
+            rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let tls = config::Tls {
+            certificate: dir.path().join("cert.pem"),
+            key: dir.path().join("key.pem"),
+        };
+        std::fs::write(&tls.certificate, cert.pem()).unwrap();
+        std::fs::write(&tls.key, key_pair.serialize_pem()).unwrap();
+        let acceptor = tls::acceptor(&tls).unwrap();
+
+        let (client, server) = tokio::io::duplex(4096);
+        let services = Services::new(Config::default()).unwrap();
+        let session = services
+            .sessions
+            .register("127.0.0.1:1234".parse().unwrap())
+            .unwrap();
+        tokio::spawn(async move {
+            let stream = acceptor.accept(server).await.unwrap();
+            serve_connection(stream, services, session).await
+        });
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(cert.der().clone()).unwrap();
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let config = rustls::ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let stream = tokio_rustls::TlsConnector::from(Arc::new(config))
+            .connect("localhost".try_into().unwrap(), client)
+            .await
+            .unwrap();
+        let (reader, writer) = tokio::io::split(stream);
+        let mut reader = FramedRead::new(reader, server::decoder::MessageDecoder);
+        let mut writer = FramedWrite::new(writer, MessageEncoder);
+        writer.send(plate()).await.unwrap();
+        assert_eq!(next_error(&mut reader).await, "You are no camera");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn disconnects_unidentified_client() {
+        let config = Config {
+            timeouts: Timeouts {
+                identification: Some(10),
+                idle: None,
+            },
+            ..Config::default()
+        };
+        let services = Services::new(config).unwrap();
+        let sessions = services.sessions.clone();
+        let session = sessions
+            .register("127.0.0.1:1234".parse().unwrap())
+            .unwrap();
+        let (writer, mut written) = futures::channel::mpsc::unbounded();
+
+        let start = tokio::time::Instant::now();
+        handle_connection(
+            futures::stream::pending(),
+            writer.sink_map_err(anyhow::Error::from),
+            services,
+            session,
+        )
+        .await
+        .unwrap();
+        assert_eq!(start.elapsed(), Duration::from_secs(10));
+        assert_eq!(
+            written.next().await,
+            Some(server::Message::Error(
+                "You took too long to identify".to_string()
+            ))
+        );
+        assert!(sessions.list().is_empty());
+    }
+
+    #[tokio::test]
+    async fn server_runs_until_shutdown() {
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = Server::builder()
+            .with_config(Config {
+                admin: Some("127.0.0.1:0".parse().unwrap()),
+                ..Config::default()
+            })
+            .with_listener(listener)
+            .with_shutdown(async move {
+                stopped.await.ok();
+            })
+            .start()
+            .await
+            .unwrap();
+        assert_ne!(server.local_addr().port(), 0);
+        let admin = server.admin_addr().unwrap();
+        assert_ne!(admin.port(), 0);
+
+        let stream = tokio::net::TcpStream::connect(server.local_addr())
+            .await
+            .unwrap();
+        let (reader, writer) = tokio::io::split(stream);
+        let mut reader = FramedRead::new(reader, server::decoder::MessageDecoder);
+        let mut writer = FramedWrite::new(writer, MessageEncoder);
+        writer.send(plate()).await.unwrap();
+        assert_eq!(next_error(&mut reader).await, "You are no camera");
+
+        let addr = server.local_addr();
+        stop.send(()).unwrap();
+        server.wait().await.unwrap();
+        assert!(tokio::net::TcpStream::connect(addr).await.is_err());
+        assert!(tokio::net::TcpStream::connect(admin).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn builder_rejects_invalid_config() {
+        let error = Server::builder()
+            .with_config(Config {
+                shards: 0,
+                ..Config::default()
+            })
+            .with_address("127.0.0.1:0".parse().unwrap())
+            .start()
+            .await
+            .unwrap_err();
+        assert_eq!(
+            format!("{error:#}"),
+            "Invalid configuration: At least one collector shard is needed"
+        );
+    }
+}
@@ -0,0 +1,164 @@
+use crate::metrics::METRICS;
+use serde::{Deserialize, Serialize};
+use speedd_codecs::{camera::Camera, Road};
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+/// What a connected client has identified as, if anything.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Unidentified,
+    Camera(Camera),
+    Dispatcher(Vec<Road>),
+}
+
+impl Role {
+    fn label(&self) -> &'static str {
+        match self {
+            Role::Unidentified => "unidentified",
+            Role::Camera(_) => "camera",
+            Role::Dispatcher(_) => "dispatcher",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Session {
+    pub id: u64,
+    pub addr: SocketAddr,
+    pub role: Role,
+}
+
+/// Registry of currently connected clients, shared between connection tasks and the admin interface.
+/// Optionally caps the number of connections per IP address.
+#[derive(Clone, Debug, Default)]
+pub struct Sessions {
+    registry: Arc<Mutex<Registry>>,
+    next_id: Arc<AtomicU64>,
+    connections_per_ip: Option<usize>,
+}
+
+#[derive(Debug, Default)]
+struct Registry {
+    sessions: BTreeMap<u64, Session>,
+    /// Number of sessions per IP address, without addresses which have none
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+impl Sessions {
+    pub fn with_connections_per_ip(mut self, limit: usize) -> Self {
+        self.connections_per_ip = Some(limit);
+        self
+    }
+
+    /// Registers a new connection. It is unregistered again when the returned guard is dropped.
+    /// Returns `None` if its IP address already has as many connections as allowed.
+    pub fn register(&self, addr: SocketAddr) -> Option<SessionGuard> {
+        let mut registry = self.registry.lock().unwrap();
+        let connections = registry.per_ip.get(&addr.ip()).copied().unwrap_or_default();
+        if self
+            .connections_per_ip
+            .is_some_and(|limit| connections >= limit)
+        {
+            return None;
+        }
+        *registry.per_ip.entry(addr.ip()).or_default() += 1;
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        METRICS
+            .connections
+            .with_label_values(&[Role::Unidentified.label()])
+            .inc();
+        registry.sessions.insert(
+            id,
+            Session {
+                id,
+                addr,
+                role: Role::Unidentified,
+            },
+        );
+        Some(SessionGuard {
+            id,
+            sessions: self.clone(),
+        })
+    }
+
+    pub fn list(&self) -> Vec<Session> {
+        let registry = self.registry.lock().unwrap();
+        registry.sessions.values().cloned().collect()
+    }
+}
+
+#[derive(Debug)]
+pub struct SessionGuard {
+    id: u64,
+    sessions: Sessions,
+}
+
+impl SessionGuard {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn set_role(&self, role: Role) {
+        let mut registry = self.sessions.registry.lock().unwrap();
+        if let Some(session) = registry.sessions.get_mut(&self.id) {
+            METRICS
+                .connections
+                .with_label_values(&[session.role.label()])
+                .dec();
+            METRICS.connections.with_label_values(&[role.label()]).inc();
+            session.role = role;
+        }
+    }
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        let mut registry = self.sessions.registry.lock().unwrap();
+        if let Some(session) = registry.sessions.remove(&self.id) {
+            let ip = session.addr.ip();
+            if let Some(connections) = registry.per_ip.get_mut(&ip) {
+                *connections -= 1;
+                if *connections == 0 {
+                    registry.per_ip.remove(&ip);
+                }
+            }
+            METRICS
+                .connections
+                .with_label_values(&[session.role.label()])
+                .dec();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn limits_connections_per_ip() {
+        let sessions = Sessions::default().with_connections_per_ip(2);
+        let first = sessions.register("10.0.0.1:1000".parse().unwrap());
+        let _second = sessions.register("10.0.0.1:1001".parse().unwrap()).unwrap();
+        assert!(sessions
+            .register("10.0.0.1:1002".parse().unwrap())
+            .is_none());
+        assert!(sessions
+            .register("10.0.0.2:1000".parse().unwrap())
+            .is_some());
+        drop(first);
+        let third = sessions.register("10.0.0.1:1002".parse().unwrap());
+        assert!(third.is_some());
+
+        // Addresses without connections are forgotten
+        drop((third, _second));
+        assert!(sessions.registry.lock().unwrap().per_ip.is_empty());
+    }
+}
@@ -0,0 +1,209 @@
+use crate::{
+    collector::{Collector, Query, RoadStatus},
+    config::Queues,
+    fines::Ledger,
+    plates::PlateRules,
+    trajectory::Trajectory,
+};
+use async_channel as mpmc;
+use itertools::Itertools;
+use speedd_codecs::{camera::Camera, plate::PlateRecord, server::TicketRecord, Road};
+use std::collections::{BTreeMap, BTreeSet};
+use tokio::sync::{mpsc, oneshot};
+
+/// Handles to a set of collectors, each running in its own task.
+///
+/// Plates are partitioned across the shards by the hash of their normalized form, so each shard sees
+/// every observation of its plates and enforces the one-ticket-per-day rule on its own. Every shard keeps its own per-road ticket queues,
+/// and dispatchers subscribe to a road on all shards.
+#[derive(Clone, Debug)]
+pub struct Shards {
+    reporting: Vec<mpsc::Sender<(PlateRecord, Camera)>>,
+    subscriptions: Vec<mpsc::Sender<(Road, oneshot::Sender<mpmc::Receiver<TicketRecord>>)>>,
+    queries: Vec<mpsc::Sender<Query>>,
+    requeue: Vec<mpsc::UnboundedSender<TicketRecord>>,
+    plates: PlateRules,
+}
+
+impl Shards {
+    pub fn spawn(collectors: Vec<Collector>, queues: &Queues) -> Self {
+        let mut shards = Self {
+            reporting: Vec::new(),
+            subscriptions: Vec::new(),
+            queries: Vec::new(),
+            requeue: Vec::new(),
+            plates: PlateRules::default(),
+        };
+        for collector in collectors {
+            let (reporting_tx, reporting_rx) = mpsc::channel(queues.reporting);
+            let (subscription_tx, subscription_rx) = mpsc::channel(queues.subscriptions);
+            let (query_tx, query_rx) = mpsc::channel(queues.queries);
+            let (requeue_tx, requeue_rx) = mpsc::unbounded_channel();
+            tokio::spawn(collector.run(reporting_rx, subscription_rx, query_rx, requeue_rx));
+            shards.reporting.push(reporting_tx);
+            shards.subscriptions.push(subscription_tx);
+            shards.queries.push(query_tx);
+            shards.requeue.push(requeue_tx);
+        }
+        shards
+    }
+
+    /// Normalizes plates like the collectors do, to route differing reads of a plate to the same shard.
+    pub fn with_plate_rules(mut self, plates: PlateRules) -> Self {
+        self.plates = plates;
+        self
+    }
+
+    /// Index of the shard responsible for a plate.
+    fn shard(&self, plate: &str) -> usize {
+        shard(&self.plates, plate, self.reporting.len())
+    }
+
+    pub async fn report(&self, record: PlateRecord, camera: Camera) -> anyhow::Result<()> {
+        let shard = self.shard(&record.plate);
+        self.reporting[shard].send((record, camera)).await?;
+        Ok(())
+    }
+
+    /// Puts a ticket which could not be delivered back into its road queue.
+    /// Does not block, so it can be called when a dispatcher is dropped.
+    pub fn requeue(&self, ticket: TicketRecord) {
+        let shard = self.shard(&ticket.plate);
+        if let Err(e) = self.requeue[shard].send(ticket) {
+            tracing::error!("Lost ticket, collector is gone: {:?}", e.0);
+        }
+    }
+
+    /// Subscribes to the tickets of a road, returning one receiver per shard.
+    pub async fn subscribe(&self, road: Road) -> anyhow::Result<Vec<mpmc::Receiver<TicketRecord>>> {
+        let mut receivers = Vec::new();
+        for subscriptions in &self.subscriptions {
+            let (tx, rx) = oneshot::channel();
+            subscriptions.send((road, tx)).await?;
+            receivers.push(rx.await?);
+        }
+        Ok(receivers)
+    }
+
+    /// Status of every road known to any shard, with queued tickets summed over the shards.
+    pub async fn roads(&self) -> anyhow::Result<Vec<RoadStatus>> {
+        let mut roads: BTreeMap<Road, RoadStatus> = BTreeMap::new();
+        for queries in &self.queries {
+            for status in Self::ask(queries, Query::Roads).await? {
+                roads
+                    .entry(status.road)
+                    .and_modify(|road| {
+                        road.limit = road.limit.or(status.limit);
+                        road.queued_tickets += status.queued_tickets;
+                        road.spilled_tickets += status.spilled_tickets;
+                        road.dispatchers = road.dispatchers.max(status.dispatchers);
+                    })
+                    .or_insert(status);
+            }
+        }
+        Ok(roads.into_values().collect_vec())
+    }
+
+    pub async fn ticketed_days(&self) -> anyhow::Result<BTreeMap<String, BTreeSet<u32>>> {
+        let mut days = BTreeMap::new();
+        for queries in &self.queries {
+            days.extend(Self::ask(queries, Query::TicketedDays).await?);
+        }
+        Ok(days)
+    }
+
+    pub async fn plate(&self, plate: String) -> anyhow::Result<Option<BTreeSet<u32>>> {
+        let queries = &self.queries[self.shard(&plate)];
+        Self::ask(queries, |tx| Query::Plate(plate, tx)).await
+    }
+
+    pub async fn ledger(&self, plate: String) -> anyhow::Result<Option<Ledger>> {
+        let queries = &self.queries[self.shard(&plate)];
+        Self::ask(queries, |tx| Query::Ledger(plate, tx)).await
+    }
+
+    pub async fn trajectory(&self, plate: String) -> anyhow::Result<Option<Trajectory>> {
+        let queries = &self.queries[self.shard(&plate)];
+        Self::ask(queries, |tx| Query::Trajectory(plate, tx)).await
+    }
+
+    /// Shuts all collectors down, returning the tickets which were not delivered.
+    pub async fn shutdown(&self) -> anyhow::Result<Vec<TicketRecord>> {
+        let mut tickets = Vec::new();
+        for queries in &self.queries {
+            tickets.extend(Self::ask(queries, Query::Shutdown).await?);
+        }
+        Ok(tickets)
+    }
+
+    /// Sends a query to a collector and waits for its answer.
+    async fn ask<T>(
+        queries: &mpsc::Sender<Query>,
+        query: impl FnOnce(oneshot::Sender<T>) -> Query,
+    ) -> anyhow::Result<T> {
+        let (tx, rx) = oneshot::channel();
+        queries.send(query(tx)).await?;
+        Ok(rx.await?)
+    }
+}
+
+/// Index of the shard out of `shards` responsible for a plate, normalized by `plates`.
+/// FNV-1a rather than the std hasher, because persisted shards must map to the same plates after a restart.
+pub fn shard(plates: &PlateRules, plate: &str, shards: usize) -> usize {
+    let key = plates
+        .normalize(plate)
+        .unwrap_or_else(|_| plate.to_string());
+    let hash = key.bytes().fold(0xcbf29ce484222325_u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    });
+    (hash % shards as u64) as usize
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::StreamExt;
+
+    #[tokio::test]
+    async fn tickets_from_all_shards_reach_dispatcher() {
+        let collectors = (0..4).map(|_| Collector::new()).collect();
+        let shards = Shards::spawn(collectors, &Queues::default());
+
+        let plates = ["ABC", "DEF", "GHI", "JKL", "MNO"];
+        for plate in plates {
+            for (timestamp, mile) in [(1, 2), (20, 4), (40, 8)] {
+                let record = PlateRecord {
+                    plate: plate.to_string(),
+                    timestamp,
+                };
+                let camera = Camera {
+                    road: 12,
+                    mile,
+                    limit: 10,
+                };
+                shards.report(record, camera).await.unwrap();
+            }
+        }
+
+        let receivers = shards.subscribe(12).await.unwrap();
+        assert_eq!(receivers.len(), 4);
+        let tickets = futures::stream::select_all(receivers)
+            .take(plates.len())
+            .map(|ticket| ticket.plate)
+            .collect::<BTreeSet<_>>()
+            .await;
+        assert_eq!(tickets, BTreeSet::from(plates.map(String::from)));
+
+        let roads = shards.roads().await.unwrap();
+        assert_eq!(
+            roads,
+            vec![RoadStatus {
+                road: 12,
+                limit: Some(10),
+                queued_tickets: 0,
+                spilled_tickets: 0,
+                dispatchers: 0,
+            }]
+        );
+    }
+}
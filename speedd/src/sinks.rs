@@ -0,0 +1,408 @@
+//! Destinations every issued ticket is forwarded to, besides the dispatchers: files the back office
+//! can ingest, or a webhook.
+
+use crate::{
+    config,
+    jsonl::{self, JsonlWriter},
+    metrics::METRICS,
+};
+use anyhow::Context;
+use futures::{future::BoxFuture, FutureExt};
+use speedd_codecs::server::TicketRecord;
+use std::{fs::File, path::Path, time::Duration};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio_util::task::TaskTracker;
+
+/// Time a webhook request may take, including the response.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Longest wait between two attempts to deliver to a webhook.
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Takes issued tickets somewhere.
+pub trait TicketSink: Send {
+    /// Names the sink in logs and metrics.
+    fn name(&self) -> &str;
+
+    /// Delivers a ticket, retrying as the sink sees fit.
+    fn send<'a>(&'a mut self, ticket: &'a TicketRecord) -> BoxFuture<'a, anyhow::Result<()>>;
+}
+
+/// Builds the sinks described in the configuration.
+pub fn from_config(sinks: &[config::Sink]) -> anyhow::Result<Vec<Box<dyn TicketSink>>> {
+    sinks
+        .iter()
+        .map(|sink| {
+            Ok(match sink {
+                config::Sink::Jsonl { path } => Box::new(JsonlSink::open(path)?) as _,
+                config::Sink::Csv { path } => Box::new(CsvSink::open(path)?) as _,
+                config::Sink::Webhook {
+                    url,
+                    retries,
+                    backoff,
+                } => Box::new(WebhookSink::new(
+                    url,
+                    *retries,
+                    Duration::from_millis(*backoff),
+                )?) as _,
+            })
+        })
+        .collect()
+}
+
+/// Handle the collectors forward tickets with. Every sink is fed from a task of its own, so slow
+/// sinks hold up neither the collectors nor each other. A sink which falls `capacity` tickets
+/// behind misses the further ones until it caught up. The tasks end once every handle is gone
+/// and they delivered what was forwarded.
+#[derive(Clone, Debug, Default)]
+pub struct Sinks {
+    senders: Vec<(String, mpsc::Sender<TicketRecord>)>,
+}
+
+impl Sinks {
+    /// Spawns the task of each sink, tracked by `tasks`.
+    pub fn spawn(sinks: Vec<Box<dyn TicketSink>>, capacity: usize, tasks: &TaskTracker) -> Self {
+        let senders = sinks
+            .into_iter()
+            .map(|sink| {
+                let (tx, rx) = mpsc::channel(capacity);
+                let name = sink.name().to_string();
+                tasks.spawn(Self::run(sink, rx));
+                (name, tx)
+            })
+            .collect();
+        Self { senders }
+    }
+
+    pub fn forward(&self, ticket: &TicketRecord) {
+        for (name, sender) in &self.senders {
+            match sender.try_send(ticket.clone()) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    tracing::warn!("{name} fell behind, dropping {ticket:?} for it");
+                    METRICS.sink_drops.with_label_values(&[name]).inc();
+                }
+                Err(TrySendError::Closed(_)) => {
+                    tracing::error!("{name} stopped, dropping {ticket:?} for it");
+                }
+            }
+        }
+    }
+
+    async fn run(mut sink: Box<dyn TicketSink>, mut tickets: mpsc::Receiver<TicketRecord>) {
+        tracing::info!("Forwarding tickets to {}", sink.name());
+        while let Some(ticket) = tickets.recv().await {
+            match sink.send(&ticket).await {
+                Ok(()) => METRICS
+                    .tickets_forwarded
+                    .with_label_values(&[sink.name()])
+                    .inc(),
+                Err(e) => {
+                    tracing::error!("Failed to forward {ticket:?} to {}: {e:#}", sink.name());
+                    METRICS
+                        .sink_failures
+                        .with_label_values(&[sink.name()])
+                        .inc();
+                }
+            }
+        }
+        tracing::info!("Stopped forwarding tickets to {}", sink.name());
+    }
+}
+
+/// Runs a write on the blocking thread pool, lending it the writer. The writer is lost if the
+/// write panics, failing every further one.
+async fn write_blocking<W: Send + 'static>(
+    writer: &mut Option<W>,
+    write: impl FnOnce(&mut W) -> anyhow::Result<()> + Send + 'static,
+) -> anyhow::Result<()> {
+    let mut lent = writer.take().context("An earlier write panicked")?;
+    let (lent, result) = tokio::task::spawn_blocking(move || {
+        let result = write(&mut lent);
+        (lent, result)
+    })
+    .await?;
+    *writer = Some(lent);
+    result
+}
+
+/// Appends tickets to a file, one JSON object per line.
+#[derive(Debug)]
+pub struct JsonlSink {
+    name: String,
+    file: Option<JsonlWriter<File>>,
+}
+
+impl JsonlSink {
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        Ok(Self {
+            name: format!("jsonl:{}", path.display()),
+            file: Some(JsonlWriter::new(jsonl::open_append(path, "ticket file")?)),
+        })
+    }
+}
+
+impl TicketSink for JsonlSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn send<'a>(&'a mut self, ticket: &'a TicketRecord) -> BoxFuture<'a, anyhow::Result<()>> {
+        let ticket = ticket.clone();
+        write_blocking(&mut self.file, move |file| file.write(&ticket)).boxed()
+    }
+}
+
+/// Appends tickets to a CSV file, which starts with a header row.
+#[derive(Debug)]
+pub struct CsvSink {
+    name: String,
+    writer: Option<csv::Writer<File>>,
+}
+
+impl CsvSink {
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let file = jsonl::open_append(path, "ticket file")?;
+        let empty = file.metadata()?.len() == 0;
+        Ok(Self {
+            name: format!("csv:{}", path.display()),
+            writer: Some(
+                csv::WriterBuilder::new()
+                    .has_headers(empty)
+                    .from_writer(file),
+            ),
+        })
+    }
+}
+
+impl TicketSink for CsvSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn send<'a>(&'a mut self, ticket: &'a TicketRecord) -> BoxFuture<'a, anyhow::Result<()>> {
+        let ticket = ticket.clone();
+        write_blocking(&mut self.writer, move |writer| {
+            writer.serialize(&ticket)?;
+            Ok(writer.flush()?)
+        })
+        .boxed()
+    }
+}
+
+/// POSTs every ticket as JSON to a URL. Failed requests are retried up to `retries` times, waiting
+/// `backoff` before the first retry and twice as long before each further one. Responses saying
+/// the request itself was wrong are not retried.
+#[derive(Debug)]
+pub struct WebhookSink {
+    name: String,
+    client: reqwest::Client,
+    url: reqwest::Url,
+    retries: u32,
+    backoff: Duration,
+}
+
+impl WebhookSink {
+    pub fn new(url: &str, retries: u32, backoff: Duration) -> anyhow::Result<Self> {
+        Ok(Self {
+            name: format!("webhook:{url}"),
+            client: reqwest::Client::builder()
+                .timeout(WEBHOOK_TIMEOUT)
+                .build()?,
+            url: url
+                .parse()
+                .with_context(|| format!("Invalid webhook URL {url:?}"))?,
+            retries,
+            backoff,
+        })
+    }
+
+    async fn post(&self, ticket: &TicketRecord) -> anyhow::Result<()> {
+        let mut backoff = self.backoff;
+        let mut attempt = 0;
+        loop {
+            let error = match self.client.post(self.url.clone()).json(ticket).send().await {
+                Ok(response) if response.status().is_success() => return Ok(()),
+                Ok(response) => {
+                    let status = response.status();
+                    let permanent = status.is_client_error()
+                        && status != reqwest::StatusCode::REQUEST_TIMEOUT
+                        && status != reqwest::StatusCode::TOO_MANY_REQUESTS;
+                    if permanent {
+                        anyhow::bail!("{} rejected the ticket with {status}", self.url);
+                    }
+                    anyhow::anyhow!("{} answered {status}", self.url)
+                }
+                Err(e) => e.into(),
+            };
+            if attempt == self.retries {
+                return Err(error.context(format!("Gave up after {} attempts", attempt + 1)));
+            }
+            attempt += 1;
+            tracing::warn!("Retrying webhook in {backoff:?}: {error:#}");
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+}
+
+impl TicketSink for WebhookSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn send<'a>(&'a mut self, ticket: &'a TicketRecord) -> BoxFuture<'a, anyhow::Result<()>> {
+        self.post(ticket).boxed()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use axum::{extract::State, http::StatusCode, routing::post, Json, Router};
+    use std::sync::{Arc, Mutex};
+
+    fn ticket(plate: &str) -> TicketRecord {
+        TicketRecord {
+            plate: plate.to_string(),
+            road: 66,
+            mile1: 100,
+            timestamp1: 123456,
+            mile2: 110,
+            timestamp2: 123816,
+            speed: 10000,
+        }
+    }
+
+    #[tokio::test]
+    async fn files_receive_every_ticket() {
+        let dir = tempfile::tempdir().unwrap();
+        let (jsonl, csv) = (dir.path().join("t.jsonl"), dir.path().join("t.csv"));
+        // Reopening a file appends without repeating the CSV header
+        for plates in [["UN1X", "RE05"], ["A,B", "X\"Y"]] {
+            let tasks = TaskTracker::new();
+            let sinks = Sinks::spawn(
+                from_config(&[
+                    config::Sink::Jsonl {
+                        path: jsonl.clone(),
+                    },
+                    config::Sink::Csv { path: csv.clone() },
+                ])
+                .unwrap(),
+                16,
+                &tasks,
+            );
+            for plate in plates {
+                sinks.forward(&ticket(plate));
+            }
+            drop(sinks);
+            tasks.close();
+            tasks.wait().await;
+        }
+
+        let plates = ["UN1X", "RE05", "A,B", "X\"Y"];
+        let lines = std::fs::read_to_string(&jsonl).unwrap();
+        let tickets = lines
+            .lines()
+            .map(|line| serde_json::from_str::<TicketRecord>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(tickets, plates.map(ticket));
+        assert_eq!(
+            std::fs::read_to_string(&csv).unwrap(),
+            "plate,road,mile1,timestamp1,mile2,timestamp2,speed\n\
+             UN1X,66,100,123456,110,123816,10000\n\
+             RE05,66,100,123456,110,123816,10000\n\
+             \"A,B\",66,100,123456,110,123816,10000\n\
+             \"X\"\"Y\",66,100,123456,110,123816,10000\n"
+        );
+    }
+
+    /// Keeps the tickets it takes.
+    struct Recorder(Arc<Mutex<Vec<TicketRecord>>>);
+
+    impl TicketSink for Recorder {
+        fn name(&self) -> &str {
+            "test:recorder"
+        }
+
+        fn send<'a>(&'a mut self, ticket: &'a TicketRecord) -> BoxFuture<'a, anyhow::Result<()>> {
+            self.0.lock().unwrap().push(ticket.clone());
+            futures::future::ready(Ok(())).boxed()
+        }
+    }
+
+    #[tokio::test]
+    async fn sink_falling_behind_misses_tickets() {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let tasks = TaskTracker::new();
+        let sinks = Sinks::spawn(vec![Box::new(Recorder(received.clone()))], 2, &tasks);
+        let drops = METRICS.sink_drops.with_label_values(&["test:recorder"]);
+        // The sink's task does not get to run in between
+        for plate in ["UN1X", "RE05", "A,B", "X\"Y"] {
+            sinks.forward(&ticket(plate));
+        }
+        assert_eq!(drops.get(), 2);
+
+        // Once it caught up, it gets tickets again
+        tokio::task::yield_now().await;
+        sinks.forward(&ticket("BACK"));
+        drop(sinks);
+        tasks.close();
+        tasks.wait().await;
+        assert_eq!(
+            *received.lock().unwrap(),
+            ["UN1X", "RE05", "BACK"].map(ticket)
+        );
+    }
+
+    /// Fails as many requests as there are statuses left, then takes the tickets.
+    #[derive(Clone, Default)]
+    struct BackOffice {
+        failures: Arc<Mutex<Vec<StatusCode>>>,
+        tickets: Arc<Mutex<Vec<TicketRecord>>>,
+    }
+
+    async fn receive(
+        State(office): State<BackOffice>,
+        Json(ticket): Json<TicketRecord>,
+    ) -> StatusCode {
+        if let Some(status) = office.failures.lock().unwrap().pop() {
+            return status;
+        }
+        office.tickets.lock().unwrap().push(ticket);
+        StatusCode::CREATED
+    }
+
+    #[tokio::test]
+    async fn webhook_retries_with_backoff() {
+        let office = BackOffice::default();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/tickets", listener.local_addr().unwrap());
+        let app = Router::new()
+            .route("/tickets", post(receive))
+            .with_state(office.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let mut webhook = WebhookSink::new(&url, 2, Duration::from_millis(10)).unwrap();
+        *office.failures.lock().unwrap() =
+            vec![StatusCode::TOO_MANY_REQUESTS, StatusCode::BAD_GATEWAY];
+        webhook.send(&ticket("UN1X")).await.unwrap();
+        assert_eq!(*office.tickets.lock().unwrap(), [ticket("UN1X")]);
+
+        // Out of retries
+        *office.failures.lock().unwrap() = vec![StatusCode::SERVICE_UNAVAILABLE; 3];
+        let error = webhook.send(&ticket("RE05")).await.unwrap_err();
+        assert_eq!(format!("{error}"), "Gave up after 3 attempts");
+
+        // Not worth retrying
+        *office.failures.lock().unwrap() = vec![StatusCode::UNPROCESSABLE_ENTITY];
+        let error = webhook.send(&ticket("RE05")).await.unwrap_err();
+        assert!(error
+            .to_string()
+            .ends_with("rejected the ticket with 422 Unprocessable Entity"));
+        assert!(office.failures.lock().unwrap().is_empty());
+        assert_eq!(office.tickets.lock().unwrap().len(), 1);
+    }
+}
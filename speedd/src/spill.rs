@@ -0,0 +1,228 @@
+use anyhow::Context;
+use speedd_codecs::server::TicketRecord;
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{BufWriter, Read, Seek, SeekFrom, Write},
+    path::PathBuf,
+};
+
+/// Size of the reads when taking spilled tickets back. A ticket takes less than 400 bytes as JSON.
+const READ_CHUNK: usize = 64 * 1024;
+
+/// A FIFO queue of tickets which keeps the oldest `threshold` tickets in memory and spills the
+/// newer ones to an anonymous file in `dir`. The file is only created once something spills,
+/// reused once it was read back completely, and removed by the OS when the queue is dropped.
+#[derive(Debug)]
+pub struct SpillQueue {
+    memory: VecDeque<TicketRecord>,
+    threshold: usize,
+    dir: PathBuf,
+    file: Option<SpillFile>,
+}
+
+/// Tickets as JSONL, appended at the end and read back from `read`.
+#[derive(Debug)]
+struct SpillFile {
+    writer: BufWriter<File>,
+    /// Offset of the oldest ticket which was not read back
+    read: u64,
+    /// Tickets which were not read back
+    len: usize,
+}
+
+impl SpillQueue {
+    pub fn new(threshold: usize, dir: PathBuf) -> Self {
+        Self {
+            memory: VecDeque::new(),
+            threshold,
+            dir,
+            file: None,
+        }
+    }
+
+    /// Tickets in the queue, in memory and on disk.
+    pub fn len(&self) -> usize {
+        self.memory.len() + self.spilled()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Tickets on disk.
+    pub fn spilled(&self) -> usize {
+        self.file.as_ref().map_or(0, |file| file.len)
+    }
+
+    pub fn push(&mut self, ticket: TicketRecord) -> anyhow::Result<()> {
+        // Once anything is on disk, newer tickets go there too to keep them in order
+        if self.spilled() == 0 && self.memory.len() < self.threshold {
+            self.memory.push_back(ticket);
+            return Ok(());
+        }
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => {
+                tracing::info!("Spilling tickets to {:?}", self.dir);
+                let file = tempfile::tempfile_in(&self.dir)
+                    .with_context(|| format!("Failed to create spill file in {:?}", self.dir))?;
+                self.file.insert(SpillFile {
+                    writer: BufWriter::new(file),
+                    read: 0,
+                    len: 0,
+                })
+            }
+        };
+        serde_json::to_writer(&mut file.writer, &ticket)?;
+        file.writer.write_all(b"\n")?;
+        file.len += 1;
+        Ok(())
+    }
+
+    /// Takes the oldest ticket. Reads the next `threshold` tickets back from disk when the memory
+    /// runs empty.
+    pub fn pop(&mut self) -> anyhow::Result<Option<TicketRecord>> {
+        if self.memory.is_empty() {
+            if let Some(file) = self.file.as_mut().filter(|file| file.len > 0) {
+                let (tickets, consumed) = file.read(self.threshold.max(1))?;
+                file.read += consumed;
+                file.len -= tickets.len();
+                if file.len == 0 {
+                    // Start over at the beginning instead of growing the file forever
+                    file.writer.seek(SeekFrom::Start(0))?;
+                    file.writer.get_ref().set_len(0)?;
+                    file.read = 0;
+                }
+                self.memory.extend(tickets);
+            }
+        }
+        Ok(self.memory.pop_front())
+    }
+
+    /// All tickets in order, leaving the queue as it is.
+    pub fn tickets(&mut self) -> anyhow::Result<Vec<TicketRecord>> {
+        self.flush()?;
+        self.iter().collect()
+    }
+
+    /// Writes out the spilled tickets which are still buffered, so [`iter`](Self::iter) sees them.
+    pub fn flush(&mut self) -> anyhow::Result<()> {
+        if let Some(file) = &mut self.file {
+            file.writer.flush()?;
+        }
+        Ok(())
+    }
+
+    /// All tickets in order, leaving the queue as it is. Spilled tickets are read back
+    /// `threshold` at a time as the iterator advances, so they never all sit in memory.
+    /// Only sees what was [`flush`](Self::flush)ed.
+    pub fn iter(&self) -> impl Iterator<Item = anyhow::Result<TicketRecord>> + '_ {
+        let chunk = self.threshold.max(1);
+        let mut spilled = self.file.as_ref().map(|file| (file, file.read, file.len));
+        let spilled = std::iter::from_fn(move || {
+            let (file, offset, len) = spilled.as_mut().filter(|(_, _, len)| *len > 0)?;
+            match file.read_at(*offset, chunk.min(*len)) {
+                Ok((tickets, consumed)) => {
+                    *offset += consumed;
+                    *len -= tickets.len();
+                    Some(tickets.into_iter().map(Ok).collect::<Vec<_>>())
+                }
+                Err(e) => {
+                    *len = 0;
+                    Some(vec![Err(e)])
+                }
+            }
+        });
+        self.memory.iter().cloned().map(Ok).chain(spilled.flatten())
+    }
+}
+
+impl SpillFile {
+    /// Reads up to `max` tickets from `read` on, without consuming them. Returns them with the
+    /// number of bytes they took.
+    fn read(&mut self, max: usize) -> anyhow::Result<(Vec<TicketRecord>, u64)> {
+        self.writer.flush()?;
+        self.read_at(self.read, max.min(self.len))
+    }
+
+    /// Reads `count` tickets from `start` on, which must be flushed already.
+    fn read_at(&self, start: u64, count: usize) -> anyhow::Result<(Vec<TicketRecord>, u64)> {
+        let mut file = self.writer.get_ref();
+        let mut tickets = Vec::new();
+        let mut offset = start;
+        let mut chunk = vec![0; READ_CHUNK];
+        while tickets.len() < count {
+            file.seek(SeekFrom::Start(offset))?;
+            let n = file.read(&mut chunk)?;
+            let mut consumed = 0;
+            for line in chunk[..n].split_inclusive(|byte| *byte == b'\n') {
+                if !line.ends_with(b"\n") || tickets.len() == count {
+                    break;
+                }
+                tickets.push(serde_json::from_slice(line).context("Corrupt spill file")?);
+                consumed += line.len();
+            }
+            anyhow::ensure!(consumed > 0, "Spill file ends in the middle of a ticket");
+            offset += consumed as u64;
+        }
+        // Appending continues at the end
+        file.seek(SeekFrom::End(0))?;
+        Ok((tickets, offset - start))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ticket(n: u32) -> TicketRecord {
+        TicketRecord {
+            plate: format!("P{n}"),
+            road: 1,
+            mile1: 0,
+            timestamp1: n,
+            mile2: 1,
+            timestamp2: n + 1,
+            speed: 36000,
+        }
+    }
+
+    #[test]
+    fn spills_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut queue = SpillQueue::new(10, dir.path().to_path_buf());
+        for n in 0..25 {
+            queue.push(ticket(n)).unwrap();
+        }
+        assert_eq!((queue.len(), queue.spilled()), (25, 15));
+        assert_eq!(
+            queue.tickets().unwrap(),
+            (0..25).map(ticket).collect::<Vec<_>>()
+        );
+
+        // The memory is refilled from disk, and newer tickets queue up behind the spilled ones
+        let popped = (0..12)
+            .map(|_| queue.pop().unwrap().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(popped, (0..12).map(ticket).collect::<Vec<_>>());
+        assert_eq!((queue.len(), queue.spilled()), (13, 5));
+        queue.push(ticket(25)).unwrap();
+        assert_eq!(queue.spilled(), 6);
+
+        let rest = std::iter::from_fn(|| queue.pop().unwrap()).collect::<Vec<_>>();
+        assert_eq!(rest, (12..26).map(ticket).collect::<Vec<_>>());
+        assert!(queue.is_empty());
+
+        // The file is reused from the start
+        for n in 0..15 {
+            queue.push(ticket(n)).unwrap();
+        }
+        let file = queue.file.as_ref().unwrap();
+        assert_eq!((file.read, file.len), (0, 5));
+        assert_eq!(
+            std::iter::from_fn(|| queue.pop().unwrap()).collect::<Vec<_>>(),
+            (0..15).map(ticket).collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,24 @@
+use crate::config::Tls;
+use anyhow::Context;
+use rustls::{
+    crypto::ring,
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    ServerConfig,
+};
+use std::sync::Arc;
+use tokio_rustls::TlsAcceptor;
+
+/// Builds the acceptor for TLS connections from the PEM encoded certificate chain and private key.
+pub fn acceptor(tls: &Tls) -> anyhow::Result<TlsAcceptor> {
+    let certificates = CertificateDer::pem_file_iter(&tls.certificate)
+        .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("Failed to read TLS certificates from {:?}", tls.certificate))?;
+    let key = PrivateKeyDer::from_pem_file(&tls.key)
+        .with_context(|| format!("Failed to read TLS key from {:?}", tls.key))?;
+    let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certificates, key)
+        .context("Invalid TLS certificate or key")?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
@@ -0,0 +1,100 @@
+use crate::collector::Sighting;
+use serde::Serialize;
+use speedd_codecs::{Mile, Road, Timestamp};
+use std::collections::{BTreeMap, HashMap};
+
+/// Everywhere a plate was seen, in time order. Only covers the observations within the retention window.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Trajectory {
+    pub plate: String,
+    pub hits: Vec<Hit>,
+}
+
+/// A camera which saw the plate.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Hit {
+    pub road: Road,
+    pub mile: Mile,
+    pub timestamp: Timestamp,
+    /// Average speed in hundredths of mph since the previous hit on the same road, if there is
+    /// one at an earlier time. Saturates at 655.35 mph
+    pub speed: Option<u16>,
+}
+
+impl Trajectory {
+    pub fn new(plate: String, roads: &HashMap<Road, BTreeMap<Timestamp, Sighting>>) -> Self {
+        let mut hits = Vec::new();
+        for (&road, observations) in roads {
+            let mut previous: Option<(Timestamp, Mile)> = None;
+            for (&timestamp, &Sighting { mile, .. }) in observations {
+                let speed = previous.and_then(|from| speed(from, (timestamp, mile)));
+                hits.push(Hit {
+                    road,
+                    mile,
+                    timestamp,
+                    speed,
+                });
+                previous = Some((timestamp, mile));
+            }
+        }
+        hits.sort_by_key(|hit| (hit.timestamp, hit.road, hit.mile));
+        Self { plate, hits }
+    }
+}
+
+/// Rounded to hundredths of mph, `None` for observations at the same time.
+fn speed((ts1, mile1): (Timestamp, Mile), (ts2, mile2): (Timestamp, Mile)) -> Option<u16> {
+    let seconds = u64::from(ts1.abs_diff(ts2));
+    if seconds == 0 {
+        return None;
+    }
+    let distance = u64::from(mile1.abs_diff(mile2)) * 3600 * 100;
+    let speed = (distance + seconds / 2) / seconds;
+    Some(speed.try_into().unwrap_or(u16::MAX))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn crosses_roads_in_time_order() {
+        let sightings = |observations: [(Timestamp, Mile); 3]| {
+            observations
+                .into_iter()
+                .map(|(timestamp, mile)| {
+                    let plate = "UN1X".to_string();
+                    (
+                        timestamp,
+                        Sighting {
+                            mile,
+                            limit: 60,
+                            plate,
+                        },
+                    )
+                })
+                .collect::<BTreeMap<_, _>>()
+        };
+        let roads = HashMap::from([
+            (1, sightings([(0, 10), (60, 11), (100, 12)])),
+            (2, sightings([(80, 5), (200, 3), (200, 4)])),
+        ]);
+        let trajectory = Trajectory::new("UN1X".to_string(), &roads);
+        let hits = trajectory
+            .hits
+            .iter()
+            .map(|hit| (hit.road, hit.mile, hit.timestamp, hit.speed))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            hits,
+            [
+                (1, 10, 0, None),
+                (1, 11, 60, Some(6000)),
+                (2, 5, 80, None),
+                (1, 12, 100, Some(9000)),
+                // A BTreeMap keeps one mile per timestamp, the last one inserted
+                (2, 4, 200, Some(3000)),
+            ]
+        );
+    }
+}
@@ -27,5 +27,113 @@ pub enum Mode {
         /// Input file
         #[arg(short, long, default_value = "sequence.ron")]
         instance: PathBuf,
+
+        /// Skip the waits between actions, so the server's throughput is the limit.
+        /// Compare runs against `speedd --shards 1` and `speedd --shards <cores>`.
+        #[arg(short, long)]
+        no_wait: bool,
+
+        /// Start a speedd with default settings in this process, listening on `server`,
+        /// and replay against it instead of an external one
+        #[arg(short, long)]
+        embedded: bool,
+
+        /// Connect with TLS, trusting the server certificates in this PEM file
+        #[arg(long)]
+        tls_ca: Option<PathBuf>,
+
+        /// Name to verify the server's TLS certificate against
+        #[arg(long, default_value = "localhost")]
+        tls_server_name: String,
+    },
+    /// Replay a sequence without waits against speedds started in this process with each number
+    /// of collector shards, counting the tickets one dispatcher for all roads receives
+    CompareShards {
+        /// Input file
+        #[arg(short, long, default_value = "sequence.ron")]
+        instance: PathBuf,
+
+        /// Numbers of collector shards to compare, 1 and the number of cores by default
+        #[arg(long, value_delimiter = ',')]
+        shards: Vec<usize>,
+
+        /// Seconds without a ticket after the replay before the dispatcher stops counting
+        #[arg(short, long, default_value_t = 1)]
+        linger: u64,
+    },
+    /// Replay client traffic captured by `speedd --capture`, with its original timing or scaled
+    Trace {
+        /// TCP server socket to connect to
+        #[arg(short, long, default_value = "0.0.0.0:8000")]
+        server: SocketAddr,
+
+        /// Capture file
+        #[arg(short, long, default_value = "capture.jsonl")]
+        capture: PathBuf,
+
+        /// Factor for the waits between messages: 0.5 replays twice as fast, 0 without waiting
+        #[arg(short, long, default_value_t = 1.0)]
+        time_scale: f64,
+
+        /// Seconds to keep the connections open after the last message, for the tickets to arrive
+        #[arg(short, long, default_value_t = 1)]
+        linger: u64,
+
+        /// Start a speedd with default settings in this process, listening on `server`,
+        /// and replay against it instead of an external one
+        #[arg(short, long)]
+        embedded: bool,
+
+        /// Connect with TLS, trusting the server certificates in this PEM file
+        #[arg(long)]
+        tls_ca: Option<PathBuf>,
+
+        /// Name to verify the server's TLS certificate against
+        #[arg(long, default_value = "localhost")]
+        tls_server_name: String,
+    },
+    /// Keep many cameras connected which only want heartbeats, and report the heartbeats received
+    /// along with the CPU time and memory the server spent on them
+    Heartbeats {
+        /// TCP server socket to connect to
+        #[arg(short, long, default_value = "0.0.0.0:8000")]
+        server: SocketAddr,
+
+        /// Start a speedd with default settings in this process, listening on `server`,
+        /// instead of connecting to an external one
+        #[arg(short, long)]
+        embedded: bool,
+
+        /// Process id of the external server, to read its CPU time and memory from /proc
+        #[arg(short, long)]
+        pid: Option<u32>,
+
+        /// Number of cameras
+        #[arg(short, long, default_value_t = 10000)]
+        connections: u16,
+
+        /// Heartbeat interval in deciseconds
+        #[arg(short, long, default_value_t = 10)]
+        interval: u32,
+
+        /// Seconds to count heartbeats for, once all cameras are connected
+        #[arg(short, long, default_value_t = 30)]
+        duration: u64,
+
+        /// Compare the per-connection heartbeat tasks speedd used before its shared heartbeat
+        /// wheel with speedd itself, each served by a child process listening on `server`
+        #[arg(long, conflicts_with_all = ["embedded", "pid"])]
+        compare: bool,
+    },
+    /// Serve the heartbeats of a `heartbeats --compare` run. Used by benchy itself.
+    #[command(hide = true)]
+    ServeHeartbeats {
+        #[arg(short, long)]
+        server: SocketAddr,
+
+        /// Spawn a task with its own interval and channel for every connection, as speedd did
+        /// before its shared heartbeat wheel, instead of running speedd
+        #[arg(long)]
+        per_connection_tasks: bool,
     },
 }
@@ -1,20 +1,25 @@
-use std::{net::SocketAddr, time::Duration};
+use std::time::Duration;
 
 use futures::SinkExt;
 use rand::prelude::SliceRandom;
 use rand::rngs::ThreadRng;
 use rand::Rng;
 use serde::{Deserialize, Serialize};
-use speedd_codecs::{camera::Camera, client, plate::PlateRecord, server};
-use tokio::{
-    io::AsyncWriteExt,
-    net::{
-        tcp::{OwnedReadHalf, OwnedWriteHalf},
-        TcpStream,
-    },
+use speedd_codecs::{
+    camera::Camera,
+    client,
+    connector::{Connection, Connector},
+    plate::PlateRecord,
+    server,
 };
+use tokio::io::{AsyncWriteExt, ReadHalf, WriteHalf};
 use tokio_util::codec::{FramedRead, FramedWrite};
 
+type Framed = (
+    FramedRead<ReadHalf<Connection>, server::decoder::MessageDecoder>,
+    FramedWrite<WriteHalf<Connection>, client::encoder::MessageEncoder>,
+);
+
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Action {
     Connect,
@@ -74,25 +79,30 @@ impl CameraClient {
         });
     }
 
-    pub async fn run(&self, addr: SocketAddr) -> anyhow::Result<()> {
-        let mut connection: Option<(
-            FramedRead<OwnedReadHalf, server::decoder::MessageDecoder>,
-            FramedWrite<OwnedWriteHalf, client::encoder::MessageEncoder>,
-        )> = None;
+    pub fn reports(&self) -> usize {
+        self.actions
+            .iter()
+            .filter(|action| matches!(action, Action::ReportPlate(_)))
+            .count()
+    }
+
+    pub async fn run(&self, connector: &Connector, no_wait: bool) -> anyhow::Result<()> {
+        let mut connection: Option<Framed> = None;
         for action in &self.actions {
             match action {
                 Action::Connect => {
                     if let Some(ref c) = connection {
                         log::error!("Already connected to {c:?}!");
                     } else {
-                        log::info!("Connecting to {addr:?}");
-                        let stream = TcpStream::connect(addr).await?;
-                        let (reader, writer) = stream.into_split();
+                        log::info!("Connecting");
+                        let stream = connector.connect().await?;
+                        let (reader, writer) = tokio::io::split(stream);
                         let reader = FramedRead::new(reader, server::decoder::MessageDecoder);
                         let writer = FramedWrite::new(writer, client::encoder::MessageEncoder);
                         connection = Some((reader, writer));
                     }
                 }
+                Action::Wait(_) if no_wait => {}
                 Action::Wait(duration) => tokio::time::sleep(*duration).await,
                 Action::RequestHeartbeat(interval) => {
                     if let Some((ref mut _reader, ref mut writer)) = connection {
@@ -123,7 +133,7 @@ impl CameraClient {
                     if let Some((reader, writer)) = connection {
                         let reader = reader.into_inner();
                         let writer = writer.into_inner();
-                        let mut stream = reader.reunite(writer)?;
+                        let mut stream = reader.unsplit(writer);
                         stream.shutdown().await?;
                         break;
                     }
@@ -0,0 +1,99 @@
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::Context;
+use futures::{future::try_join_all, SinkExt, StreamExt};
+use speedd::config::Config;
+use speedd_codecs::{client, connector::Connector, server};
+use tokio::{sync::oneshot, time::Instant};
+use tokio_util::codec::{FramedRead, FramedWrite};
+
+use crate::sequence::Sequence;
+
+/// How an embedded speedd with a given number of collector shards took a sequence, replayed
+/// without waits, as seen by a single dispatcher for all roads.
+#[derive(Clone, Copy, Debug)]
+pub struct ShardRun {
+    pub shards: usize,
+    /// Time until every camera sent its reports
+    pub replay: Duration,
+    pub tickets: u64,
+    /// Time from the start until the dispatcher received the last ticket
+    pub last_ticket: Duration,
+}
+
+impl ShardRun {
+    /// Starts a fresh server for every run, so the runs do not share state. The dispatcher stops
+    /// counting once no ticket arrived for `linger` after the replay.
+    pub async fn run(sequence: Sequence, shards: usize, linger: Duration) -> anyhow::Result<Self> {
+        let (stop, stopped) = oneshot::channel::<()>();
+        let server = speedd::Server::builder()
+            .with_config(Config {
+                address: SocketAddr::from((Ipv4Addr::LOCALHOST, 0)),
+                shards,
+                ..Config::default()
+            })
+            .with_shutdown(async {
+                let _ = stopped.await;
+            })
+            .start()
+            .await?;
+        let connector = Connector::new(server.local_addr());
+
+        let (reader, writer) = tokio::io::split(connector.connect().await?);
+        let mut writer = FramedWrite::new(writer, client::encoder::MessageEncoder);
+        writer
+            .send(client::Message::IAmDispatcher(sequence.road_ids()))
+            .await?;
+        let mut reader = FramedRead::new(reader, server::decoder::MessageDecoder);
+
+        let start = Instant::now();
+        let replayed = Arc::new(AtomicBool::new(false));
+        let counter = tokio::spawn({
+            let replayed = replayed.clone();
+            async move {
+                let (mut tickets, mut last_ticket) = (0, Duration::ZERO);
+                loop {
+                    match tokio::time::timeout(linger, reader.next()).await {
+                        Ok(Some(message)) => {
+                            if let server::Message::Ticket(_) = message? {
+                                tickets += 1;
+                                last_ticket = start.elapsed();
+                            }
+                        }
+                        Ok(None) => anyhow::bail!("Server closed the dispatcher connection"),
+                        Err(_) if replayed.load(Ordering::Relaxed) => break,
+                        Err(_) => {}
+                    }
+                }
+                anyhow::Ok((tickets, last_ticket))
+            }
+        });
+
+        try_join_all(sequence.run(connector, true).await?)
+            .await
+            .context("Failed to join")?
+            .into_iter()
+            .collect::<anyhow::Result<Vec<_>>>()
+            .context("Failed to run camera tasks")?;
+        let replay = start.elapsed();
+        replayed.store(true, Ordering::Relaxed);
+        let (tickets, last_ticket) = counter.await??;
+
+        drop(writer);
+        let _ = stop.send(());
+        server.wait().await?;
+        Ok(Self {
+            shards,
+            replay,
+            tickets,
+            last_ticket,
+        })
+    }
+}
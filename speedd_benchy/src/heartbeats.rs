@@ -0,0 +1,300 @@
+use std::{
+    net::SocketAddr,
+    process::Stdio,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use futures::{SinkExt, StreamExt};
+use speedd_codecs::{camera::Camera, client, connector::Connector, server};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    process::Command,
+    sync::mpsc,
+};
+use tokio_util::codec::{FramedRead, FramedWrite};
+
+/// Holds many cameras which do nothing but receive heartbeats, to show what the server's
+/// heartbeat scheduling costs at large connection counts.
+/// Remember to raise the open file limit (`ulimit -n`) for more than a few thousand connections.
+pub struct HeartbeatLoad {
+    pub connections: u16,
+    pub interval: Duration,
+    pub duration: Duration,
+}
+
+/// What a load measured.
+pub struct HeartbeatRun {
+    pub received: u64,
+    pub expected: f64,
+    pub elapsed: Duration,
+    /// Before connecting, once connected, once heartbeating and at the end, if `/proc` is
+    /// available
+    pub stats: Option<[ProcessStats; 4]>,
+}
+
+impl HeartbeatRun {
+    /// Memory the server spent on the heartbeats of each camera, in kB.
+    fn memory_per_heartbeat(&self, connections: u16) -> Option<f64> {
+        self.stats.map(|[_, connected, heartbeating, _]| {
+            (heartbeating.rss_kb as f64 - connected.rss_kb as f64) / f64::from(connections)
+        })
+    }
+
+    /// CPU time the server spent while heartbeating, in clock ticks per second.
+    fn cpu_per_second(&self) -> Option<f64> {
+        self.stats.map(|[_, _, heartbeating, after]| {
+            (after.cpu_ticks - heartbeating.cpu_ticks) as f64 / self.elapsed.as_secs_f64()
+        })
+    }
+}
+
+/// CPU time and memory of a process, from `/proc` (Linux only).
+#[derive(Clone, Copy, Debug)]
+pub struct ProcessStats {
+    /// User and system time in clock ticks, usually hundredths of a second
+    cpu_ticks: u64,
+    /// Resident set size in kB
+    rss_kb: u64,
+}
+
+impl ProcessStats {
+    /// Reads the stats of the process `pid`, or of this process.
+    pub fn read(pid: Option<u32>) -> anyhow::Result<Self> {
+        let dir = pid.map_or("/proc/self".to_string(), |pid| format!("/proc/{pid}"));
+        let stat = std::fs::read_to_string(format!("{dir}/stat"))?;
+        // The fields after the parenthesized command name, starting with the state (field 3)
+        let fields = stat
+            .rsplit_once(')')
+            .map(|(_, rest)| rest.split_whitespace().collect::<Vec<_>>())
+            .unwrap_or_default();
+        let field = |n: usize| -> anyhow::Result<u64> {
+            Ok(fields
+                .get(n - 3)
+                .ok_or_else(|| anyhow::anyhow!("Truncated {dir}/stat"))?
+                .parse()?)
+        };
+        let cpu_ticks = field(14)? + field(15)?;
+        let status = std::fs::read_to_string(format!("{dir}/status"))?;
+        let rss_kb = status
+            .lines()
+            .find_map(|line| line.strip_prefix("VmRSS:"))
+            .and_then(|rss| rss.trim().trim_end_matches("kB").trim().parse().ok())
+            .ok_or_else(|| anyhow::anyhow!("No VmRSS in {dir}/status"))?;
+        Ok(Self { cpu_ticks, rss_kb })
+    }
+}
+
+impl HeartbeatLoad {
+    /// Connects all cameras, then counts their heartbeats for the duration.
+    /// `pid` is the server process to measure, `None` for an embedded server.
+    pub async fn run(
+        &self,
+        connector: &Connector,
+        pid: Option<u32>,
+    ) -> anyhow::Result<HeartbeatRun> {
+        let received = Arc::new(AtomicU64::new(0));
+        let before = ProcessStats::read(pid).ok();
+        let mut handles = Vec::with_capacity(self.connections.into());
+        let mut writers = Vec::with_capacity(self.connections.into());
+        for mile in 0..self.connections {
+            let stream = connector.connect().await?;
+            let (reader, writer) = tokio::io::split(stream);
+            let mut reader = FramedRead::new(reader, server::decoder::MessageDecoder);
+            let mut writer = FramedWrite::new(writer, client::encoder::MessageEncoder);
+            writer
+                .send(client::Message::IAmCamera(Camera {
+                    road: 1,
+                    mile,
+                    limit: 60,
+                }))
+                .await?;
+            let received = received.clone();
+            handles.push(tokio::spawn(async move {
+                while let Some(msg) = reader.next().await {
+                    match msg? {
+                        server::Message::Heartbeat => {
+                            received.fetch_add(1, Ordering::Relaxed);
+                        }
+                        other => anyhow::bail!("Unexpected {other:?}"),
+                    }
+                }
+                Ok(())
+            }));
+            writers.push(writer);
+        }
+        let connected = ProcessStats::read(pid).ok();
+        println!("Connected {} cameras", self.connections);
+
+        // Asking only now separates what the heartbeats cost from what the connections cost
+        for writer in &mut writers {
+            writer
+                .send(client::Message::WantHeartbeat(self.interval))
+                .await?;
+        }
+        // Servers may round the heartbeats to a grid, so start counting half an interval off it
+        tokio::time::sleep(self.interval / 2).await;
+        let heartbeating = ProcessStats::read(pid).ok();
+        received.store(0, Ordering::Relaxed);
+        let start = Instant::now();
+        tokio::time::sleep(self.duration).await;
+        let elapsed = start.elapsed();
+        let received = received.load(Ordering::Relaxed);
+        let after = ProcessStats::read(pid).ok();
+        for handle in &handles {
+            if handle.is_finished() {
+                anyhow::bail!("A camera was disconnected");
+            }
+            handle.abort();
+        }
+        drop(writers);
+
+        let expected =
+            elapsed.as_secs_f64() / self.interval.as_secs_f64() * f64::from(self.connections);
+        println!(
+            "Received {received} heartbeats in {elapsed:?}, {:.1}% of the expected {expected:.0}",
+            received as f64 / expected * 100.0
+        );
+        let run = HeartbeatRun {
+            received,
+            expected,
+            elapsed,
+            stats: before.zip(connected).zip(heartbeating).zip(after).map(
+                |(((before, connected), heartbeating), after)| {
+                    [before, connected, heartbeating, after]
+                },
+            ),
+        };
+        match run.stats {
+            Some([before, connected, heartbeating, after]) => {
+                println!(
+                    "Memory: {} kB before connecting, {} kB connected, {} kB heartbeating, \
+                     {} kB at the end",
+                    before.rss_kb, connected.rss_kb, heartbeating.rss_kb, after.rss_kb
+                );
+                println!(
+                    "CPU time while heartbeating: {} ticks ({:.1} ticks/s)",
+                    after.cpu_ticks - heartbeating.cpu_ticks,
+                    (after.cpu_ticks - heartbeating.cpu_ticks) as f64 / elapsed.as_secs_f64()
+                );
+                if pid.is_none() {
+                    println!("The embedded server's numbers include this client's own share");
+                }
+            }
+            None => println!("No process statistics, /proc is not available"),
+        }
+        Ok(run)
+    }
+
+    /// Runs the load against the per-connection heartbeat tasks speedd used before its shared
+    /// heartbeat wheel, then against speedd, each in a fresh child process, and compares them.
+    pub async fn compare(&self, address: SocketAddr) -> anyhow::Result<()> {
+        let mut runs = Vec::new();
+        for per_connection_tasks in [true, false] {
+            let scheme = if per_connection_tasks {
+                "per-connection tasks"
+            } else {
+                "shared wheel"
+            };
+            println!("Heartbeats from {scheme}:");
+            let mut command = Command::new(std::env::current_exe()?);
+            command
+                .args(["serve-heartbeats", "--server", &address.to_string()])
+                .stdout(Stdio::null())
+                .kill_on_drop(true);
+            if per_connection_tasks {
+                command.arg("--per-connection-tasks");
+            }
+            let mut child = command.spawn().context("Failed to start the server")?;
+            let connector = Connector::new(address);
+            let mut attempts = 0;
+            while connector.connect().await.is_err() {
+                attempts += 1;
+                if attempts == 100 {
+                    anyhow::bail!("The {scheme} server does not listen on {address}");
+                }
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+            let run = self.run(&connector, child.id()).await;
+            child.kill().await?;
+            runs.push((scheme, run?));
+        }
+
+        println!("{:<32}{:>24}{:>24}", "", runs[0].0, runs[1].0);
+        let row = |name: &str, value: &dyn Fn(&HeartbeatRun) -> Option<f64>, unit: &str| {
+            let cell = |run: &HeartbeatRun| {
+                value(run).map_or("-".to_string(), |value| format!("{value:.1} {unit}"))
+            };
+            println!("{name:<32}{:>24}{:>24}", cell(&runs[0].1), cell(&runs[1].1));
+        };
+        row(
+            "Heartbeats received",
+            &|run| Some(run.received as f64 / run.expected * 100.0),
+            "%",
+        );
+        row(
+            "Memory per heartbeating camera",
+            &|run| run.memory_per_heartbeat(self.connections),
+            "kB",
+        );
+        row(
+            "CPU time while heartbeating",
+            &HeartbeatRun::cpu_per_second,
+            "ticks/s",
+        );
+        Ok(())
+    }
+}
+
+/// Serves heartbeats the way speedd did before its shared heartbeat wheel: every `WantHeartbeat`
+/// spawns a task with its own interval, which signals the connection through a channel.
+/// Everything else the cameras send is ignored.
+pub async fn serve_per_connection_tasks(address: SocketAddr) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(address).await?;
+    loop {
+        let (stream, _) = listener.accept().await?;
+        tokio::spawn(async move {
+            if let Err(e) = serve_connection(stream).await {
+                log::debug!("Connection failed: {e}");
+            }
+        });
+    }
+}
+
+async fn serve_connection(stream: TcpStream) -> anyhow::Result<()> {
+    let (reader, writer) = stream.into_split();
+    let mut reader = FramedRead::new(reader, client::decoder::MessageDecoder);
+    let mut writer = FramedWrite::new(writer, server::encoder::MessageEncoder);
+    let (heartbeat_sender, mut heartbeat_receiver) = mpsc::channel(16);
+    let mut heartbeat_sender = Some(heartbeat_sender);
+    loop {
+        tokio::select! {
+            msg = reader.next() => match msg.transpose()? {
+                Some(client::Message::WantHeartbeat(interval)) if !interval.is_zero() => {
+                    if let Some(sender) = heartbeat_sender.take() {
+                        tokio::spawn(heartbeat(interval, sender));
+                    }
+                }
+                Some(_) => {}
+                None => return Ok(()),
+            },
+            Some(()) = heartbeat_receiver.recv() => {
+                writer.send(server::Message::Heartbeat).await?;
+            }
+        }
+    }
+}
+
+async fn heartbeat(interval: Duration, sender: mpsc::Sender<()>) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        if sender.send(()).await.is_err() {
+            return;
+        }
+    }
+}
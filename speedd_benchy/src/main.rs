@@ -3,14 +3,25 @@
 use anyhow::Context;
 use arguments::{Arguments, Mode};
 use clap::Parser;
+use compare_shards::ShardRun;
 use futures::future::try_join_all;
+use heartbeats::HeartbeatLoad;
 use landscape::Landscape;
 use sequence::Sequence;
+use speedd_codecs::connector::Connector;
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+use trace::Trace;
 
 mod arguments;
 mod camera_client;
+mod compare_shards;
+mod heartbeats;
 mod landscape;
 mod sequence;
+mod trace;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -28,19 +39,150 @@ async fn main() -> anyhow::Result<()> {
             )
             .context("Failed to write sequence file")?;
         }
-        Mode::Replay { server, instance } => {
+        Mode::Replay {
+            server,
+            instance,
+            no_wait,
+            embedded,
+            tls_ca,
+            tls_server_name,
+        } => {
+            let server = if embedded {
+                start_embedded(server).await?
+            } else {
+                server
+            };
+            let mut connector = Connector::new(server);
+            if let Some(ca) = &tls_ca {
+                connector = connector.with_tls(ca, &tls_server_name)?;
+            }
             let input = std::fs::read_to_string(instance)?;
             let sequence: Sequence = ron::from_str(&input)?;
 
-            let handles = sequence.run(server).await?;
+            let reports = sequence.reports();
+            let start = Instant::now();
+            let handles = sequence.run(connector, no_wait).await?;
             try_join_all(handles)
                 .await
                 .context("Failed to join")?
                 .into_iter()
                 .collect::<anyhow::Result<Vec<_>>>()
                 .context("Failed to run camera tasks")?;
+            let elapsed = start.elapsed();
+            println!(
+                "Sent {reports} plate reports in {elapsed:?} ({:.0} reports/s)",
+                reports as f64 / elapsed.as_secs_f64()
+            );
+        }
+        Mode::CompareShards {
+            instance,
+            mut shards,
+            linger,
+        } => {
+            if shards.is_empty() {
+                shards = vec![1, std::thread::available_parallelism()?.get()];
+            }
+            let input = std::fs::read_to_string(instance)?;
+            let sequence: Sequence = ron::from_str(&input)?;
+            let reports = sequence.reports();
+            let mut runs = Vec::new();
+            for shards in shards {
+                let run =
+                    ShardRun::run(sequence.clone(), shards, Duration::from_secs(linger)).await?;
+                println!(
+                    "{:>3} shards: sent {reports} plate reports in {:?} ({:.0} reports/s), \
+                     dispatcher received {} tickets, the last after {:?}",
+                    run.shards,
+                    run.replay,
+                    reports as f64 / run.replay.as_secs_f64(),
+                    run.tickets,
+                    run.last_ticket
+                );
+                runs.push(run);
+            }
+            if runs.iter().any(|run| run.tickets != runs[0].tickets) {
+                println!("The shard counts disagree on the tickets!");
+            }
+        }
+        Mode::Trace {
+            server,
+            capture,
+            time_scale,
+            linger,
+            embedded,
+            tls_ca,
+            tls_server_name,
+        } => {
+            let server = if embedded {
+                start_embedded(server).await?
+            } else {
+                server
+            };
+            let mut connector = Connector::new(server);
+            if let Some(ca) = &tls_ca {
+                connector = connector.with_tls(ca, &tls_server_name)?;
+            }
+            let trace = Trace::from_file(capture)?;
+            let (connections, messages) = (trace.connections(), trace.messages());
+            let start = Instant::now();
+            let received = trace
+                .run(connector, time_scale, Duration::from_secs(linger))
+                .await?;
+            println!(
+                "Replayed {messages} messages of {connections} connections in {:?}, \
+                 received {} tickets and {} errors",
+                start.elapsed(),
+                received.tickets,
+                received.errors
+            );
+        }
+        Mode::Heartbeats {
+            server,
+            embedded,
+            pid,
+            connections,
+            interval,
+            duration,
+            compare,
+        } => {
+            let load = HeartbeatLoad {
+                connections,
+                interval: Duration::from_millis(u64::from(interval) * 100),
+                duration: Duration::from_secs(duration),
+            };
+            if compare {
+                load.compare(server).await?;
+            } else {
+                let server = if embedded {
+                    start_embedded(server).await?
+                } else {
+                    server
+                };
+                load.run(&Connector::new(server), pid).await?;
+            }
+        }
+        Mode::ServeHeartbeats {
+            server,
+            per_connection_tasks,
+        } => {
+            if per_connection_tasks {
+                heartbeats::serve_per_connection_tasks(server).await?;
+            } else {
+                start_embedded(server).await?;
+                std::future::pending::<()>().await;
+            }
         }
     }
 
     Ok(())
 }
+
+/// Starts a speedd with default settings in this process. It runs until the process exits.
+async fn start_embedded(address: SocketAddr) -> anyhow::Result<SocketAddr> {
+    let server = speedd::Server::builder()
+        .with_address(address)
+        .start()
+        .await?;
+    println!("Started speedd on {}", server.local_addr());
+    Ok(server.local_addr())
+}
@@ -1,12 +1,13 @@
 use std::{
     collections::{BTreeMap, HashSet},
-    net::SocketAddr,
     time::Duration,
 };
 
 use rand::prelude::*;
 use serde::{Deserialize, Serialize};
-use speedd_codecs::{camera::Camera, plate::PlateRecord, Mile, SECONDS_PER_DAY};
+use speedd_codecs::{
+    camera::Camera, connector::Connector, plate::PlateRecord, Mile, SECONDS_PER_DAY,
+};
 use tokio::task::JoinHandle;
 
 use crate::{
@@ -137,14 +138,30 @@ fn license_plate(rng: &mut ThreadRng) -> String {
 }
 
 impl Sequence {
+    pub fn road_ids(&self) -> Vec<u16> {
+        self.roads.iter().map(|road| road.id).collect()
+    }
+
+    pub fn reports(&self) -> usize {
+        self.roads
+            .iter()
+            .flat_map(|road| road.cameras.values())
+            .map(CameraClient::reports)
+            .sum()
+    }
+
     pub async fn run(
         self,
-        addr: SocketAddr,
+        connector: Connector,
+        no_wait: bool,
     ) -> anyhow::Result<Vec<JoinHandle<anyhow::Result<()>>>> {
         let mut handles = Vec::new();
         for road in self.roads {
             for (_, camera) in road.cameras {
-                handles.push(tokio::spawn(async move { camera.run(addr).await }));
+                let connector = connector.clone();
+                handles.push(tokio::spawn(async move {
+                    camera.run(&connector, no_wait).await
+                }));
             }
         }
         Ok(handles)
@@ -0,0 +1,133 @@
+use std::{
+    collections::BTreeMap,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use futures::{future::join_all, SinkExt, StreamExt};
+use speedd_codecs::{client, connector::Connector, server};
+use tokio::time::Instant;
+use tokio_util::codec::{FramedRead, FramedWrite};
+
+/// Client traffic captured by `speedd --capture`, replayed connection by connection.
+/// Every connection is opened when its first message arrived and sends each message when it
+/// arrived, relative to the first message of the capture.
+pub struct Trace {
+    connections: BTreeMap<u64, Vec<(Duration, client::Message)>>,
+}
+
+/// What the server answered during a replay.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Received {
+    pub tickets: u64,
+    pub errors: u64,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    tickets: AtomicU64,
+    errors: AtomicU64,
+}
+
+impl Trace {
+    pub fn from_file(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let captured = speedd::read_capture(path)?;
+        let first = captured.iter().map(|m| m.micros).min().unwrap_or_default();
+        let mut connections = BTreeMap::<_, Vec<_>>::new();
+        for m in captured {
+            connections
+                .entry(m.connection)
+                .or_default()
+                .push((Duration::from_micros(m.micros - first), m.message));
+        }
+        Ok(Self { connections })
+    }
+
+    pub fn connections(&self) -> usize {
+        self.connections.len()
+    }
+
+    pub fn messages(&self) -> usize {
+        self.connections.values().map(Vec::len).sum()
+    }
+
+    /// Replays the trace with its waits multiplied by `time_scale`: 0.5 is twice as fast, 0 does
+    /// not wait at all. The connections stay open for `linger` after the last message was sent,
+    /// so dispatchers can receive the tickets it causes.
+    pub async fn run(
+        self,
+        connector: Connector,
+        time_scale: f64,
+        linger: Duration,
+    ) -> anyhow::Result<Received> {
+        anyhow::ensure!(
+            time_scale.is_finite() && time_scale >= 0.0,
+            "Invalid time scale {time_scale}"
+        );
+        let received = Arc::new(Counters::default());
+        let start = Instant::now();
+        let handles = self
+            .connections
+            .into_iter()
+            .map(|(id, messages)| {
+                let connector = connector.clone();
+                let received = received.clone();
+                tokio::spawn(async move {
+                    let at = |at: Duration| start + at.mul_f64(time_scale);
+                    let Some(&(first, _)) = messages.first() else {
+                        return anyhow::Ok(None);
+                    };
+                    tokio::time::sleep_until(at(first)).await;
+                    let (reader, writer) = tokio::io::split(connector.connect().await?);
+                    let reader = FramedRead::new(reader, server::decoder::MessageDecoder);
+                    let reader = tokio::spawn(Self::receive(reader, received));
+                    let mut writer = FramedWrite::new(writer, client::encoder::MessageEncoder);
+                    for (arrival, message) in messages {
+                        tokio::time::sleep_until(at(arrival)).await;
+                        if let Err(e) = writer.send(message).await {
+                            // The captured client may have been disconnected just the same
+                            log::warn!("Connection {id} was closed by the server: {e}");
+                            break;
+                        }
+                    }
+                    Ok(Some((writer, reader)))
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut connections = Vec::new();
+        for handle in join_all(handles).await {
+            connections.push(handle??);
+        }
+        tokio::time::sleep(linger).await;
+        for (_, reader) in connections.into_iter().flatten() {
+            reader.abort();
+        }
+        Ok(Received {
+            tickets: received.tickets.load(Ordering::Relaxed),
+            errors: received.errors.load(Ordering::Relaxed),
+        })
+    }
+
+    async fn receive<R>(mut reader: R, received: Arc<Counters>)
+    where
+        R: futures::Stream<Item = anyhow::Result<server::Message>> + Unpin,
+    {
+        while let Some(Ok(message)) = reader.next().await {
+            match message {
+                server::Message::Ticket(_) => {
+                    received.tickets.fetch_add(1, Ordering::Relaxed);
+                }
+                server::Message::Error(error) => {
+                    log::debug!("Server error: {error}");
+                    received.errors.fetch_add(1, Ordering::Relaxed);
+                }
+                server::Message::Heartbeat => {}
+            }
+        }
+    }
+}
@@ -1,6 +1,6 @@
 use anyhow::Context;
 use clap::{Parser, Subcommand};
-use std::{net::SocketAddr, time::Duration};
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
 
 fn parse_hex_digit(s: &str) -> anyhow::Result<u16> {
     u16::from_str_radix(s, 16).context("Failed to parse hex")
@@ -13,6 +13,14 @@ pub struct Arguments {
     #[arg(short, long, default_value = "0.0.0.0:8000")]
     pub address: SocketAddr,
 
+    /// Connect with TLS, trusting the server certificates in this PEM file
+    #[arg(long)]
+    pub tls_ca: Option<PathBuf>,
+
+    /// Name to verify the server's TLS certificate against
+    #[arg(long, default_value = "localhost")]
+    pub tls_server_name: String,
+
     /// Heartbeat interval duration (off by default)
     #[arg(short, long, default_value_t = Duration::ZERO.into())]
     pub interval: humantime::Duration,
@@ -27,6 +35,10 @@ pub enum Mode {
     Dispatcher {
         #[arg(value_delimiter = ' ', value_parser = parse_hex_digit)]
         roads: Vec<u16>,
+
+        /// Acknowledge every received ticket (protocol extension)
+        #[arg(long)]
+        acks: bool,
     },
     Camera {
         /// Road ID
@@ -5,11 +5,11 @@ use rustyline::{error::ReadlineError, history::DefaultHistory};
 use speedd_codecs::{
     camera::Camera,
     client::{self, encoder::MessageEncoder as Encoder},
+    connector::Connector,
     plate::PlateRecord,
-    server::decoder::MessageDecoder as Decoder,
+    server::{self, decoder::MessageDecoder as Decoder},
 };
 use std::time::Duration;
-use tokio::net::TcpStream;
 use tokio_util::codec::{FramedRead, FramedWrite};
 
 mod arguments;
@@ -18,8 +18,12 @@ mod arguments;
 async fn main() -> anyhow::Result<()> {
     let args = Arguments::parse();
 
-    let client = TcpStream::connect(args.address).await?;
-    let (reader, writer) = client.into_split();
+    let mut connector = Connector::new(args.address);
+    if let Some(ca) = &args.tls_ca {
+        connector = connector.with_tls(ca, &args.tls_server_name)?;
+    }
+    let client = connector.connect().await?;
+    let (reader, writer) = tokio::io::split(client);
 
     let mut reader = FramedRead::new(reader, Decoder);
     let mut writer = FramedWrite::new(writer, Encoder);
@@ -80,15 +84,21 @@ async fn main() -> anyhow::Result<()> {
                 }
             }
         }
-        Mode::Dispatcher { roads } => {
+        Mode::Dispatcher { roads, acks } => {
             println!("Registering as dispatcher");
             writer.send(client::Message::IAmDispatcher(roads)).await?;
+            if acks {
+                writer.send(client::Message::WantAcks).await?;
+            }
 
             println!("Start listening loop");
             loop {
                 match reader.next().await {
                     Some(Ok(next)) => {
                         println!("{next:?}");
+                        if acks && matches!(next, server::Message::Ticket(_)) {
+                            writer.send(client::Message::Ack(1)).await?;
+                        }
                     }
                     Some(Err(e)) => println!("{e:?}"),
                     None => {
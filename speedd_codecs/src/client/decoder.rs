@@ -19,18 +19,16 @@ impl Decoder for MessageDecoder {
                     return Ok(None);
                 };
                 if src.remaining() >= 1 + 1 + *len as usize + 4 {
-                    let bytes = std::str::from_utf8(&src[2..2 + *len as usize]).unwrap();
-                    let timestamp = u32::from_be_bytes(
-                        src[2 + *len as usize..2 + *len as usize + 4]
-                            .try_into()
-                            .unwrap(),
-                    );
-                    let plate = Self::Item::Plate(PlateRecord {
-                        plate: bytes.to_string(),
-                        timestamp,
-                    });
-                    src.advance(1 + 1 + *len as usize + 4);
-                    Ok(Some(plate))
+                    let len = *len as usize;
+                    let plate = std::str::from_utf8(&src[2..2 + len]).map(str::to_string);
+                    let timestamp =
+                        u32::from_be_bytes(src[2 + len..2 + len + 4].try_into().unwrap());
+                    // Skip the whole message either way, so the next one can be decoded
+                    src.advance(1 + 1 + len + 4);
+                    let Ok(plate) = plate else {
+                        anyhow::bail!("Plate is not valid UTF-8");
+                    };
+                    Ok(Some(Self::Item::Plate(PlateRecord { plate, timestamp })))
                 } else {
                     Ok(None)
                 }
@@ -77,6 +75,20 @@ impl Decoder for MessageDecoder {
                 src.advance(1 + 1 + *len as usize * 2);
                 Ok(Some(Self::Item::IAmDispatcher(bytes)))
             }
+            Some(0x82) => {
+                src.advance(1);
+                Ok(Some(Self::Item::WantAcks))
+            }
+            Some(0x83) => {
+                #[allow(clippy::int_plus_one)]
+                if src.remaining() >= 1 + 4 {
+                    src.advance(1); // tag byte
+                    let count = src.get_u32();
+                    Ok(Some(Self::Item::Ack(count)))
+                } else {
+                    Ok(None)
+                }
+            }
             Some(n) => anyhow::bail!("Invalid opcode 0x{n:x}"),
             None => Ok(None),
         }
@@ -109,6 +121,19 @@ mod test {
         assert_eq!(expected, second);
     }
 
+    #[test]
+    fn rejects_invalid_utf8_plate() {
+        let mut input = BytesMut::from(&[0x20, 0x02, 0xc3, 0x28, 0x00, 0x00, 0x00, 0x01, 0x82][..]);
+
+        let mut decoder = MessageDecoder;
+        let error = decoder.decode(&mut input).unwrap_err();
+        assert_eq!(error.to_string(), "Plate is not valid UTF-8");
+        assert_eq!(
+            decoder.decode(&mut input).unwrap(),
+            Some(client::Message::WantAcks)
+        );
+    }
+
     #[test]
     fn dispatcher_example() {
         let mut input = BytesMut::from(&[0x81, 0x03, 0x00, 0x42, 0x01, 0x70, 0x13, 0x88][..]);
@@ -121,4 +146,17 @@ mod test {
         let expected = client::Message::IAmDispatcher(vec![66, 368, 5000]);
         assert_eq!(expected, second);
     }
+
+    #[test]
+    fn ack_example() {
+        let mut input = BytesMut::from(&[0x82, 0x83, 0x00, 0x00, 0x01, 0x02][..]);
+
+        let mut decoder = MessageDecoder;
+        let want_acks = decoder.decode(&mut input).unwrap().unwrap();
+        assert_eq!(client::Message::WantAcks, want_acks);
+
+        let ack = decoder.decode(&mut input).unwrap().unwrap();
+        assert_eq!(client::Message::Ack(258), ack);
+        assert!(input.is_empty());
+    }
 }
@@ -13,7 +13,7 @@ impl Encoder<Message> for MessageEncoder {
         match item {
             Message::Plate(PlateRecord { plate, timestamp }) => {
                 dst.put_u8(0x20);
-                dst.put_u8(plate.as_bytes().len() as u8);
+                dst.put_u8(plate.len() as u8);
                 dst.put_slice(plate.as_bytes());
                 dst.put_u32(timestamp);
                 Ok(())
@@ -39,6 +39,15 @@ impl Encoder<Message> for MessageEncoder {
                 }
                 Ok(())
             }
+            Message::WantAcks => {
+                dst.put_u8(0x82);
+                Ok(())
+            }
+            Message::Ack(count) => {
+                dst.put_u8(0x83);
+                dst.put_u32(count);
+                Ok(())
+            }
         }
     }
 }
@@ -11,4 +11,8 @@ pub enum Message {
     WantHeartbeat(Duration),
     IAmCamera(Camera),
     IAmDispatcher(Vec<u16>),
+    /// Protocol extension: the dispatcher will acknowledge tickets with [`Message::Ack`]
+    WantAcks,
+    /// Protocol extension: the dispatcher has processed this many more tickets, in delivery order
+    Ack(u32),
 }
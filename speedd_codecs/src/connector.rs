@@ -0,0 +1,55 @@
+use anyhow::Context;
+use rustls::{
+    crypto::ring,
+    pki_types::{pem::PemObject, CertificateDer, ServerName},
+    ClientConfig, RootCertStore,
+};
+use std::{net::SocketAddr, path::Path, sync::Arc};
+use tokio::net::TcpStream;
+use tokio_rustls::{client::TlsStream, TlsConnector};
+use tokio_util::either::Either;
+
+pub type Connection = Either<TcpStream, TlsStream<TcpStream>>;
+
+/// Opens connections to the server, over TLS if configured.
+#[derive(Clone)]
+pub struct Connector {
+    addr: SocketAddr,
+    tls: Option<(TlsConnector, ServerName<'static>)>,
+}
+
+impl Connector {
+    pub fn new(addr: SocketAddr) -> Self {
+        Self { addr, tls: None }
+    }
+
+    /// Speaks TLS, verifying the server against the PEM encoded certificates in `ca` and `server_name`.
+    pub fn with_tls(mut self, ca: &Path, server_name: &str) -> anyhow::Result<Self> {
+        let mut roots = RootCertStore::empty();
+        for certificate in CertificateDer::pem_file_iter(ca)
+            .with_context(|| format!("Failed to read CA certificates from {ca:?}"))?
+        {
+            roots.add(certificate?)?;
+        }
+        let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let server_name = ServerName::try_from(server_name.to_string())?;
+        self.tls = Some((TlsConnector::from(Arc::new(config)), server_name));
+        Ok(self)
+    }
+
+    pub async fn connect(&self) -> anyhow::Result<Connection> {
+        let stream = TcpStream::connect(self.addr).await?;
+        Ok(match &self.tls {
+            Some((connector, server_name)) => Either::Right(
+                connector
+                    .connect(server_name.clone(), stream)
+                    .await
+                    .context("TLS handshake failed")?,
+            ),
+            None => Either::Left(stream),
+        })
+    }
+}
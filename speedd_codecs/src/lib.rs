@@ -2,6 +2,8 @@
 
 pub mod camera;
 pub mod client;
+#[cfg(feature = "connector")]
+pub mod connector;
 pub mod plate;
 pub mod server;
 
@@ -26,7 +26,7 @@ impl Encoder<Message> for MessageEncoder {
                 speed,
             }) => {
                 dst.put_u8(0x21);
-                dst.put_u8(plate.as_bytes().len() as u8);
+                dst.put_u8(plate.len() as u8);
                 dst.put_slice(plate.as_bytes());
                 dst.put_u16(road);
                 dst.put_u16(mile1);
@@ -1,9 +1,6 @@
 use bytes::{BufMut, BytesMut};
 use tokio_util::codec::Encoder;
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
-pub struct Response(i32);
-
 #[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
 pub struct ResponseEncoder;
 